@@ -0,0 +1,103 @@
+use humantime::parse_duration;
+use std::{collections::BTreeMap, time::Duration};
+
+/// Team owning the profile.
+pub const OWNER: &str = "owner";
+
+/// AWS account ID the profile belongs to.
+pub const ACCOUNT_ID: &str = "account_id";
+
+/// Free-form description of the profile.
+pub const DESCRIPTION: &str = "description";
+
+/// Longest time the profile can be unlocked for, e.g. `30m`. Tightens the tier policy.
+pub const MAX_DURATION: &str = "max_duration";
+
+/// Phrase to type in to confirm unlocking the profile.
+pub const CONFIRM: &str = "confirm";
+
+/// Metadata attached to a profile by `# aws-unlock: key=value` lines above its header. Keys are
+/// lowercase; keys other than the well-known ones above are kept as is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Annotations(BTreeMap<String, String>);
+
+impl Annotations {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Sets the annotation, or removes it if `value` is empty. Fails with a message if the
+    /// value is invalid for the key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let key = key.to_ascii_lowercase();
+        let value = value.trim();
+        if value.is_empty() {
+            self.0.remove(&key);
+            return Ok(());
+        }
+
+        validate(&key, value)?;
+        self.0.insert(key, value.to_string());
+
+        Ok(())
+    }
+
+    /// Sets the annotation as read from a file without validating it, so that an invalid value
+    /// is written back rather than lost. See [`Annotations::invalid`].
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.0
+            .insert(key.to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    /// Annotations with invalid values, with the reason.
+    pub fn invalid(&self) -> impl Iterator<Item = (&str, String)> {
+        self.iter()
+            .filter_map(|(key, value)| validate(key, value).err().map(|reason| (key, reason)))
+    }
+
+    /// Adds the annotations of `other`, which take precedence.
+    pub fn merge(&mut self, other: Annotations) {
+        self.0.extend(other.0);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Fails with a message if the value is not a duration.
+    pub fn max_duration(&self) -> Result<Option<Duration>, String> {
+        self.get(MAX_DURATION)
+            .map(|value| parse_duration(value).map_err(|e| format!("invalid duration: {e}")))
+            .transpose()
+    }
+
+    pub fn confirm_phrase(&self) -> Option<&str> {
+        self.get(CONFIRM)
+    }
+}
+
+/// Checks the value of a well-known key.
+pub fn validate(key: &str, value: &str) -> Result<(), String> {
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(format!("invalid key {key:?}"));
+    }
+    if value.contains(['\r', '\n']) {
+        return Err("values cannot span lines".to_string());
+    }
+
+    match key {
+        MAX_DURATION => parse_duration(value)
+            .map(|_| ())
+            .map_err(|e| format!("invalid duration: {e}")),
+        ACCOUNT_ID if value.len() != 12 || !value.bytes().all(|b| b.is_ascii_digit()) => {
+            Err("expected a 12-digit account ID".to_string())
+        }
+        _ => Ok(()),
+    }
+}
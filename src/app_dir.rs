@@ -0,0 +1,155 @@
+use home::home_dir;
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::error::Result;
+
+/// Returns `~/.aws-unlock`, the directory aws-unlock keeps its own state in. The directory is
+/// created with owner-only permissions if it does not exist yet.
+pub fn app_dir() -> Result<PathBuf> {
+    let home_dir = home_dir().expect("failed to locate home directory");
+    let app_dir = home_dir.join(".aws-unlock");
+    create_private_dir(&app_dir)?;
+
+    Ok(app_dir)
+}
+
+/// Encodes `name`, such as a profile name, as a file name. Bytes other than ASCII letters,
+/// digits, `-` and `_` are percent-encoded, so that names containing `/` or `..` stay in the
+/// directory and different names never share a file.
+pub fn encode_file_name(name: &str) -> String {
+    name.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
+
+/// Creates `path` and its parents if missing and restricts it to the current user (0700).
+pub fn create_private_dir(path: &Path) -> Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    {
+        use std::{
+            fs::{set_permissions, Permissions},
+            os::unix::fs::{DirBuilderExt, PermissionsExt},
+        };
+        builder.mode(0o700);
+        builder.create(path)?;
+        set_permissions(path, Permissions::from_mode(0o700))?;
+    }
+
+    #[cfg(not(unix))]
+    builder.create(path)?;
+
+    Ok(())
+}
+
+/// Writes `contents` to `path`, readable and writable by the current user only (0600).
+///
+/// The contents are written to a temporary file in the same directory, synced and renamed over
+/// `path`, so that a crash or a full disk leaves either the old or the new contents, never a
+/// truncated file.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{file_name}.{}.tmp", process::id()));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let res = (|| {
+        let mut file = options.open(&tmp_path)?;
+        #[cfg(unix)]
+        {
+            use std::{
+                fs::{set_permissions, Permissions},
+                os::unix::fs::PermissionsExt,
+            };
+            // The mode only applies to newly created files, e.g. not to one left by a crash
+            set_permissions(&tmp_path, Permissions::from_mode(0o600))?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if let Err(e) = res {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    // Make the rename itself durable
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Appends `contents` to `path`, creating it readable and writable by the current user only
+/// (0600).
+pub fn append_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_stay_in_the_directory() {
+        assert_eq!(encode_file_name("team-dev_1"), "team-dev_1");
+        assert_eq!(encode_file_name("team/../dev"), "team%2F%2E%2E%2Fdev");
+        assert_ne!(encode_file_name("a b"), encode_file_name("a_b"));
+    }
+
+    #[test]
+    fn write_private_file_replaces_the_file() {
+        let dir = std::env::temp_dir().join(format!("aws-unlock-app-dir-test-{}", process::id()));
+        create_private_dir(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        write_private_file(&path, b"old contents").unwrap();
+        write_private_file(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        // No temporary file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
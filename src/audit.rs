@@ -0,0 +1,47 @@
+use humantime::format_rfc3339_seconds;
+use serde::{Deserialize, Serialize};
+use std::{process, time::SystemTime};
+
+use crate::{
+    app_dir::{app_dir, append_private_file},
+    error::Result,
+};
+
+/// Entry of ~/.aws-unlock/audit.log, a JSON line written whenever profiles of tiers that require
+/// confirmation are unlocked.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// RFC 3339 time of the unlock.
+    pub time: String,
+
+    /// Process that unlocked the profiles.
+    pub pid: u32,
+
+    /// Profiles unlocked, with their tiers, e.g. `prod (production)`.
+    pub profiles: Vec<String>,
+
+    /// What the profiles were unlocked for, e.g. `until 2024-01-01T00:00:00Z` or the command.
+    pub purpose: String,
+
+    /// Reason given at the confirmation prompt.
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(profiles: Vec<String>, purpose: String, reason: Option<String>) -> Self {
+        Self {
+            time: format_rfc3339_seconds(SystemTime::now()).to_string(),
+            pid: process::id(),
+            profiles,
+            purpose,
+            reason,
+        }
+    }
+
+    /// Appends the record to the audit log.
+    pub fn write(&self) -> Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        append_private_file(&app_dir()?.join("audit.log"), &line)
+    }
+}
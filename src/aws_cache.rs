@@ -0,0 +1,408 @@
+use home::home_dir;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    app_dir::{app_dir, create_private_dir},
+    aws_profile::AwsProfile,
+    error::Result,
+};
+
+/// What to do with the cached credentials of a profile when it gets locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum CachePolicy {
+    /// Move cache files into aws-unlock's private directory and restore them on unlock.
+    #[default]
+    Quarantine,
+
+    /// Delete cache files. The next unlock requires a fresh login or role assumption.
+    Purge,
+
+    /// Leave cache files untouched.
+    Keep,
+}
+
+/// Kind of AWS credential cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    /// `~/.aws/cli/cache`: assumed-role and SSO role credentials.
+    Cli,
+
+    /// `~/.aws/sso/cache`: SSO access tokens.
+    Sso,
+}
+
+impl CacheKind {
+    fn relative_dir(self) -> &'static Path {
+        match self {
+            CacheKind::Cli => Path::new("cli/cache"),
+            CacheKind::Sso => Path::new("sso/cache"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheFile {
+    pub kind: CacheKind,
+    pub file_name: String,
+}
+
+/// AWS CLI/SSO credential caches, and the quarantine area aws-unlock moves them to while their
+/// profiles are locked.
+#[derive(Debug)]
+pub struct AwsCache {
+    aws_home: PathBuf,
+    quarantine_dir: PathBuf,
+}
+
+impl AwsCache {
+    pub fn open() -> Result<AwsCache> {
+        let home_dir = home_dir().expect("failed to locate home directory");
+        let quarantine_dir = app_dir()?.join("quarantine");
+        create_private_dir(&quarantine_dir)?;
+
+        Ok(AwsCache {
+            aws_home: home_dir.join(".aws"),
+            quarantine_dir,
+        })
+    }
+
+    /// Lists cache files currently present that belong to the profile.
+    pub fn cache_files(&self, profile: &AwsProfile) -> Result<Vec<CacheFile>> {
+        find_files(&self.aws_home, profile)
+    }
+
+    /// Quarantines or purges the given cache files according to the policy.
+    pub fn lock(&self, files: &[CacheFile], policy: CachePolicy) -> Result<()> {
+        for file in files {
+            let path = self.cache_path(file);
+            match policy {
+                CachePolicy::Quarantine => {
+                    let dest = self.quarantine_path(file);
+                    create_private_dir(dest.parent().expect("quarantine path has a parent"))?;
+                    move_file(&path, &dest)?;
+                }
+                CachePolicy::Purge => remove_file_if_exists(&path)?,
+                CachePolicy::Keep => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves quarantined cache files of the profile back to the AWS caches, including those
+    /// quarantined along with another profile sharing them, such as the token of a common SSO
+    /// session. A cache file created in the meantime, for example by a fresh `aws sso login`,
+    /// wins over the quarantined one.
+    pub fn unlock(&self, profile: &AwsProfile) -> Result<()> {
+        for file in find_files(&self.quarantine_dir, profile)? {
+            let quarantined = self.quarantine_path(&file);
+            let dest = self.cache_path(&file);
+            if dest.exists() {
+                remove_file_if_exists(&quarantined)?;
+            } else {
+                fs::create_dir_all(dest.parent().expect("cache path has a parent"))?;
+                move_file(&quarantined, &dest)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cache_path(&self, file: &CacheFile) -> PathBuf {
+        file.path_in(&self.aws_home)
+    }
+
+    fn quarantine_path(&self, file: &CacheFile) -> PathBuf {
+        file.path_in(&self.quarantine_dir)
+    }
+}
+
+impl CacheFile {
+    /// Path of the file under `root`, which is laid out like `~/.aws`.
+    fn path_in(&self, root: &Path) -> PathBuf {
+        root.join(self.kind.relative_dir()).join(&self.file_name)
+    }
+}
+
+/// Lists cache files under `root`, laid out like `~/.aws`, that belong to the profile.
+///
+/// SSO token and SSO role credential caches are located by the same SHA-1 cache keys the AWS CLI
+/// uses. Assumed-role caches are keyed by every parameter of the AssumeRole call, so they are
+/// identified by the assumed-role ARN recorded inside instead.
+fn find_files(root: &Path, profile: &AwsProfile) -> Result<Vec<CacheFile>> {
+    let conf = &profile.data.conf;
+    let mut files = vec![];
+
+    if let Some(token_key) = conf.sso_session.as_ref().or(conf.sso_start_url.as_ref()) {
+        files.push(CacheFile {
+            kind: CacheKind::Sso,
+            file_name: cache_file_name(token_key),
+        });
+    }
+
+    if let (Some(start_url), Some(account_id), Some(role_name)) = (
+        conf.resolved_sso_start_url(),
+        &conf.sso_account_id,
+        &conf.sso_role_name,
+    ) {
+        let mut args = json!({
+            "startUrl": start_url,
+            "accountId": account_id,
+            "roleName": role_name,
+        });
+        if let Some(session_name) = &conf.sso_session {
+            args["sessionName"] = json!(session_name);
+        }
+        files.push(CacheFile {
+            kind: CacheKind::Cli,
+            file_name: cache_file_name(&args.to_string()),
+        });
+    }
+
+    if let Some(role) = conf
+        .role_arn
+        .as_deref()
+        .and_then(RoleIdentity::from_role_arn)
+    {
+        for entry in read_dir_if_exists(&root.join(CacheKind::Cli.relative_dir()))? {
+            let entry = entry?;
+            let Ok(contents) = fs::read_to_string(entry.path()) else {
+                continue;
+            };
+            let Ok(cache) = serde_json::from_str::<Value>(&contents) else {
+                continue;
+            };
+            let assumed = cache["AssumedRoleUser"]["Arn"]
+                .as_str()
+                .and_then(RoleIdentity::from_assumed_role_arn);
+            if assumed.as_ref() == Some(&role) {
+                files.push(CacheFile {
+                    kind: CacheKind::Cli,
+                    file_name: entry.file_name().to_string_lossy().into_owned(),
+                });
+            }
+        }
+    }
+
+    files.retain(|file| file.path_in(root).exists());
+    files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    files.dedup();
+
+    Ok(files)
+}
+
+/// Account and role name, the parts shared between a role ARN and its assumed-role ARN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RoleIdentity {
+    account_id: String,
+    role_name: String,
+}
+
+impl RoleIdentity {
+    /// Parses `arn:aws:iam::123456789012:role/path/RoleName`.
+    fn from_role_arn(arn: &str) -> Option<Self> {
+        let (account_id, resource) = split_arn(arn, "iam")?;
+        let role_name = resource.strip_prefix("role/")?.rsplit('/').next()?;
+        Some(Self {
+            account_id: account_id.to_string(),
+            role_name: role_name.to_string(),
+        })
+    }
+
+    /// Parses `arn:aws:sts::123456789012:assumed-role/RoleName/session-name`.
+    fn from_assumed_role_arn(arn: &str) -> Option<Self> {
+        let (account_id, resource) = split_arn(arn, "sts")?;
+        let role_name = resource.strip_prefix("assumed-role/")?.split('/').next()?;
+        Some(Self {
+            account_id: account_id.to_string(),
+            role_name: role_name.to_string(),
+        })
+    }
+}
+
+fn split_arn<'a>(arn: &'a str, service: &str) -> Option<(&'a str, &'a str)> {
+    let ["arn", _partition, arn_service, _region, account_id, resource] =
+        *arn.splitn(6, ':').collect::<Vec<_>>()
+    else {
+        return None;
+    };
+
+    (arn_service == service).then_some((account_id, resource))
+}
+
+fn cache_file_name(key: &str) -> String {
+    format!("{:x}.json", Sha1::digest(key.as_bytes()))
+}
+
+fn read_dir_if_exists(path: &Path) -> Result<Vec<std::io::Result<fs::DirEntry>>> {
+    match fs::read_dir(path) {
+        Ok(entries) => Ok(entries.collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_err() {
+        // rename(2) does not work across file systems
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        aws_profile::{AwsConfigData, AwsProfileData},
+        line_parser::LockState,
+        tier::Tier,
+    };
+    use std::env;
+
+    const ROLE_ARN: &str = "arn:aws:iam::123456789012:role/ops/Deployer";
+
+    fn profile(name: &str, conf: AwsConfigData) -> AwsProfile {
+        AwsProfile {
+            name: name.into(),
+            tier: Tier::Dev,
+            rule_match: None,
+            annotations: Default::default(),
+            lock_state: LockState::Unlocked,
+            data: AwsProfileData { conf, cred: None },
+        }
+    }
+
+    /// Caches in a temporary directory holding an SSO token of the `corp` session and the
+    /// credentials of two assumed roles.
+    fn cache(name: &str) -> AwsCache {
+        let dir = env::temp_dir().join(format!("aws-unlock-cache-{name}-{}", std::process::id()));
+        let cache = AwsCache {
+            aws_home: dir.join(".aws"),
+            quarantine_dir: dir.join("quarantine"),
+        };
+        let sso_dir = cache.aws_home.join(CacheKind::Sso.relative_dir());
+        let cli_dir = cache.aws_home.join(CacheKind::Cli.relative_dir());
+        fs::create_dir_all(&sso_dir).unwrap();
+        fs::create_dir_all(&cli_dir).unwrap();
+
+        fs::write(sso_dir.join(cache_file_name("corp")), "{}").unwrap();
+        for (file_name, arn) in [
+            (
+                "deployer.json",
+                "arn:aws:sts::123456789012:assumed-role/Deployer/botocore-session-1",
+            ),
+            (
+                "reader.json",
+                "arn:aws:sts::123456789012:assumed-role/Reader/botocore-session-2",
+            ),
+        ] {
+            let contents = json!({ "AssumedRoleUser": { "Arn": arn } });
+            fs::write(cli_dir.join(file_name), contents.to_string()).unwrap();
+        }
+
+        cache
+    }
+
+    fn remove(cache: AwsCache) {
+        fs::remove_dir_all(cache.aws_home.parent().unwrap()).unwrap();
+    }
+
+    fn file_names(files: &[CacheFile]) -> Vec<&str> {
+        files.iter().map(|file| file.file_name.as_str()).collect()
+    }
+
+    #[test]
+    fn quarantined_files_are_restored_on_unlock() {
+        let cache = cache("quarantine");
+        let deployer = profile(
+            "deployer",
+            AwsConfigData {
+                role_arn: Some(ROLE_ARN.to_string()),
+                sso_session: Some("corp".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let files = cache.cache_files(&deployer).unwrap();
+        let token = cache_file_name("corp");
+        assert_eq!(file_names(&files), ["deployer.json", token.as_str()]);
+
+        cache.lock(&files, CachePolicy::Quarantine).unwrap();
+        assert_eq!(cache.cache_files(&deployer).unwrap(), []);
+        let reader = cache.aws_home.join("cli/cache/reader.json");
+        assert!(reader.exists());
+
+        cache.unlock(&deployer).unwrap();
+        assert_eq!(cache.cache_files(&deployer).unwrap(), files);
+        remove(cache);
+    }
+
+    #[test]
+    fn fresh_files_win_over_quarantined_ones() {
+        let cache = cache("fresh");
+        let sso = profile(
+            "sso",
+            AwsConfigData {
+                sso_session: Some("corp".to_string()),
+                ..Default::default()
+            },
+        );
+        let files = cache.cache_files(&sso).unwrap();
+        cache.lock(&files, CachePolicy::Quarantine).unwrap();
+
+        let token = cache.cache_path(&files[0]);
+        fs::write(&token, "fresh").unwrap();
+        cache.unlock(&sso).unwrap();
+        assert_eq!(fs::read_to_string(&token).unwrap(), "fresh");
+        assert!(!cache.quarantine_path(&files[0]).exists());
+        remove(cache);
+    }
+
+    #[test]
+    fn purged_files_are_deleted() {
+        let cache = cache("purge");
+        let sso = profile(
+            "sso",
+            AwsConfigData {
+                sso_session: Some("corp".to_string()),
+                ..Default::default()
+            },
+        );
+        let files = cache.cache_files(&sso).unwrap();
+        cache.lock(&files, CachePolicy::Purge).unwrap();
+        cache.unlock(&sso).unwrap();
+        assert_eq!(cache.cache_files(&sso).unwrap(), []);
+        remove(cache);
+    }
+
+    #[test]
+    fn role_identities_ignore_paths_and_session_names() {
+        let role = RoleIdentity::from_role_arn(ROLE_ARN).unwrap();
+        assert_eq!(
+            RoleIdentity::from_assumed_role_arn(
+                "arn:aws:sts::123456789012:assumed-role/Deployer/session"
+            ),
+            Some(role)
+        );
+        assert_eq!(
+            RoleIdentity::from_role_arn("arn:aws:sts::123456789012:role/Deployer"),
+            None
+        );
+    }
+}
@@ -1,36 +1,152 @@
-use std::{
-    collections::HashMap,
-    io::{stdin, stdout, Write},
-};
+use std::{collections::HashMap, fmt, path::Path, time::SystemTime};
 
-use anyhow::{bail, Result};
+use humantime::{format_duration, format_rfc3339_seconds};
 use itertools::Itertools;
 
-use crate::aws_profile::{AwsFile, AwsProfile, ProfileName};
+use crate::{
+    annotation,
+    audit::AuditRecord,
+    aws_cache::{AwsCache, CachePolicy},
+    aws_profile::{AwsFile, AwsProfile, DuplicatePolicy, ProfileName},
+    confirm::Confirmer,
+    error::{Error, Result},
+    lease::Lease,
+    line_parser::LockState,
+    settings::{account_id, CommandVerdict, ConfirmMode, Confirmation, ReasonPolicy, Settings},
+    tier::Tier,
+    totp::Totp,
+    vault::{is_sealed, Vault},
+};
+
+/// What profiles are unlocked for, checked against the policies of their tiers.
+#[derive(Debug, Clone, Copy)]
+pub enum Purpose<'a> {
+    /// Until the deadline, e.g. for `--seconds` or `serve --for`.
+    Until(SystemTime),
+
+    /// While the command runs.
+    Command(&'a [String]),
+}
+
+impl fmt::Display for Purpose<'_> {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Purpose::Until(expires_at) => {
+                write!(b, "until {}", format_rfc3339_seconds(*expires_at))
+            }
+            Purpose::Command(command) => write!(b, "{}", command.join(" ")),
+        }
+    }
+}
+
+impl Purpose<'_> {
+    /// Deadline recorded in the unlock leases, if the caller relocks at a known time.
+    pub fn expires_at(self) -> Option<SystemTime> {
+        match self {
+            Purpose::Until(expires_at) => Some(expires_at),
+            Purpose::Command(_) => None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct AwsLockGuard<'a> {
     pub target_profiles: &'a [ProfileName],
     pub profiles: Vec<AwsProfile>,
+    cache_policy: CachePolicy,
+    on_duplicate: DuplicatePolicy,
+    vault: Option<Vault>,
+    sealed_profiles: Vec<ProfileName>,
+}
+
+/// State of the checks on unlocking shared by the unlocks and joins of one run.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnlockChecks {
+    /// Whether the targets were selected by a pattern, which easily matches more than intended.
+    /// Questions mention it, and production profiles are confirmed whatever their tier policy.
+    pub selected_by_pattern: bool,
+
+    /// Whether a TOTP code was already verified, so that profiles unlocked and joined in the
+    /// same run ask for a single code. A code is accepted only once.
+    pub is_totp_verified: bool,
 }
 
 impl<'a> AwsLockGuard<'a> {
+    /// Unlocks the target profiles until the guard is dropped. Fails if `purpose` is not allowed
+    /// by the tier policy of any target. Asks `confirmer` before unlocking tiers that require
+    /// confirmation, except for profiles it approves, and fails if it is not interactive.
+    /// `checks` carries what the checks of earlier unlocks and joins of the run found.
+    ///
+    /// Secrets of profiles locked in the vault are decrypted back into ~/.aws/credentials, unless
+    /// `inject_vault_secrets` is set: then they only appear in [`AwsLockGuard::profiles`] for
+    /// the caller to pass on, and ~/.aws/credentials keeps the placeholders.
+    #[allow(clippy::too_many_arguments)]
     pub fn unlock(
         target_profiles: &'a [ProfileName],
         error_if_not_exist: bool,
-        warn_on_production: bool,
+        confirmer: &mut dyn Confirmer,
+        cache_policy: CachePolicy,
+        on_duplicate: DuplicatePolicy,
+        purpose: Purpose,
+        inject_vault_secrets: bool,
+        checks: &mut UnlockChecks,
     ) -> Result<Self> {
-        let profiles = modify_lock_status(
+        let mut vault = None;
+        let mut sealed_profiles = vec![];
+        let mut injected_creds = vec![];
+        let mut profiles = modify_lock_status(
             target_profiles,
             error_if_not_exist,
-            warn_on_production,
             false,
+            cache_policy,
+            on_duplicate,
+            &mut |targets, settings| {
+                let record = check_tier_policies(targets, settings, purpose, checks, confirmer)?;
+                record.map_or(Ok(()), |record| record.write())
+            },
+            &mut |profile| {
+                let Some(cred) = profile.data.cred.as_mut().filter(|cred| is_sealed(cred)) else {
+                    return Ok(());
+                };
+                if vault.is_none() {
+                    vault = Some(Vault::open()?);
+                }
+                let vault = vault.as_ref().expect("vault is opened above");
+                sealed_profiles.push(profile.name.clone());
+                if inject_vault_secrets {
+                    // Decrypted before the files are written, as nothing relocks them if this
+                    // fails afterwards
+                    let mut injected = cred.clone();
+                    vault.unseal(&profile.name, &mut injected)?;
+                    injected_creds.push((profile.name.clone(), injected));
+                } else {
+                    vault.unseal(&profile.name, cred)?;
+                }
+
+                Ok(())
+            },
         )?;
 
-        Ok(Self {
+        for (name, injected) in injected_creds {
+            if let Some(profile) = profiles.iter_mut().find(|p| p.name == name) {
+                profile.data.cred = Some(injected);
+            }
+        }
+
+        // Construct the guard first so that the profiles get relocked if acquiring fails
+        let guard = Self {
             target_profiles,
             profiles,
-        })
+            cache_policy,
+            on_duplicate,
+            vault,
+            sealed_profiles,
+        };
+        for name in target_profiles {
+            Lease::acquire(name, purpose.expires_at())?;
+        }
+
+        Ok(guard)
     }
 
     pub fn lock(self) {
@@ -40,17 +156,123 @@ impl<'a> AwsLockGuard<'a> {
 
 impl Drop for AwsLockGuard<'_> {
     fn drop(&mut self) {
-        let _ = modify_lock_status(self.target_profiles, false, false, true);
+        for name in self.target_profiles {
+            let _ = Lease::release(name);
+        }
+        let vault = &mut self.vault;
+        let sealed_profiles = &self.sealed_profiles;
+        let res = modify_lock_status(
+            self.target_profiles,
+            false,
+            true,
+            self.cache_policy,
+            self.on_duplicate,
+            &mut |_, _| Ok(()),
+            &mut |profile| {
+                // Secrets are saved into the vault before placeholders are written to the file
+                if let (Some(vault), Some(cred)) = (vault.as_mut(), profile.data.cred.as_mut()) {
+                    if sealed_profiles.contains(&profile.name) {
+                        let unsealed = cred.clone();
+                        vault.seal(&profile.name, cred);
+                        if let Err(e) = vault.save() {
+                            // Commented out secrets are still better than unlocked ones
+                            eprintln!(
+                                "Failed to save the vault, locking '{}' by commenting it out \
+                                 instead: {e}",
+                                profile.name
+                            );
+                            *cred = unsealed;
+                        }
+                    }
+                }
+
+                Ok(())
+            },
+        );
+
+        // Drop cannot return the error, and the user must know the profiles are still unlocked
+        if let Err(e) = res {
+            eprintln!(
+                "Failed to relock {}: {e}",
+                self.target_profiles
+                    .iter()
+                    .map(|name| format!("'{name}'"))
+                    .join(", ")
+            );
+        }
+    }
+}
+
+/// Joins the sessions of other aws-unlock processes holding `target_profiles` unlocked, for the
+/// caller to use them without unlocking or relocking them. Fails unless each of them has an
+/// active lease and `purpose` is allowed by its tier policy. Asks before joining tiers that
+/// require confirmation like [`AwsLockGuard::unlock`]. Returns all profiles, with the secrets of
+/// the targets decrypted if they are in the vault.
+pub fn join_sessions(
+    target_profiles: &[ProfileName],
+    confirmer: &mut dyn Confirmer,
+    on_duplicate: DuplicatePolicy,
+    purpose: Purpose,
+    checks: &mut UnlockChecks,
+) -> Result<Vec<AwsProfile>> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
+    let mut profiles = aws_file.parse()?;
+
+    let mut not_joinable = vec![];
+    for name in target_profiles {
+        let is_unlocked = profiles
+            .iter()
+            .any(|p| p.name == *name && p.lock_state == LockState::Unlocked);
+        if !is_unlocked || Lease::active(name)?.is_none() {
+            not_joinable.push(name.clone());
+        }
     }
+    if !not_joinable.is_empty() {
+        return Err(Error::NotJoinable(not_joinable));
+    }
+
+    let targets: Vec<_> = profiles
+        .iter()
+        .filter(|p| target_profiles.contains(&p.name))
+        .collect();
+    let record = check_tier_policies(&targets, aws_file.settings(), purpose, checks, confirmer)?;
+    record.map_or(Ok(()), |record| record.write())?;
+
+    // Secrets injected into the command of the other session never reached the file
+    let mut vault = None;
+    for profile in &mut profiles {
+        let Some(cred) = profile
+            .data
+            .cred
+            .as_mut()
+            .filter(|cred| is_sealed(cred) && target_profiles.contains(&profile.name))
+        else {
+            continue;
+        };
+        if vault.is_none() {
+            vault = Some(Vault::open()?);
+        }
+        vault
+            .as_ref()
+            .expect("vault is opened above")
+            .unseal(&profile.name, cred)?;
+    }
+
+    Ok(profiles)
 }
 
+/// Locks or unlocks the target profiles. `check` sees the existing targets before anything is
+/// modified and can abort with an error; `update` is applied to each of them.
 fn modify_lock_status(
     target_profiles: &[ProfileName],
     error_if_not_exist: bool,
-    warn_on_production: bool,
     lock: bool,
+    cache_policy: CachePolicy,
+    on_duplicate: DuplicatePolicy,
+    check: &mut dyn FnMut(&[&AwsProfile], &Settings) -> Result<()>,
+    update: &mut dyn FnMut(&mut AwsProfile) -> Result<()>,
 ) -> Result<Vec<AwsProfile>> {
-    let mut aws_file = AwsFile::open()?;
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
 
     let mut profiles = aws_file.parse()?;
     let profile_indices: HashMap<_, _> = profiles
@@ -64,51 +286,715 @@ fn modify_lock_status(
         let unknown_profiles: Vec<_> = target_profiles
             .iter()
             .filter(|name| !profile_indices.contains_key(name))
+            .cloned()
             .collect();
 
         if !unknown_profiles.is_empty() {
-            let unknown_profiles = unknown_profiles
-                .into_iter()
-                .map(|s| format!("'{s}'"))
-                .format(", ");
-            bail!("unknown profiles: {unknown_profiles}",);
+            return Err(Error::UnknownProfiles(unknown_profiles));
         }
     }
 
-    if warn_on_production {
-        // Warn if target profile contains production profile
-        let production_profiles: Vec<_> = target_profiles
+    if !lock {
+        // Unlocking would uncomment whatever the user has commented out by hand
+        let inconsistent_profiles: Vec<_> = target_profiles
             .iter()
             .filter(|name| profile_indices.contains_key(name))
-            .filter(|name| profiles[profile_indices[name]].is_production)
+            .filter(|name| profiles[profile_indices[name]].lock_state == LockState::Inconsistent)
+            .cloned()
             .collect();
 
-        if !production_profiles.is_empty() {
-            print!(
-                "You are unlocking production profiles: {}. Are you sure? (y/N) ",
-                production_profiles
-                    .into_iter()
-                    .map(|s| format!("'{s}'"))
-                    .format(", ")
-            );
-            stdout().flush()?;
-            let mut buf = String::new();
-            stdin().read_line(&mut buf)?;
-            if !["y", "Y"].contains(&buf.trim()) {
-                bail!("Unlocking production profiles cancelled by user");
-            }
+        if !inconsistent_profiles.is_empty() {
+            return Err(Error::Inconsistent(inconsistent_profiles));
         }
     }
 
+    let targets: Vec<_> = target_profiles
+        .iter()
+        .filter_map(|name| profile_indices.get(name))
+        .map(|&index| &profiles[index])
+        .collect();
+    check(&targets, aws_file.settings())?;
+
     // Lock target profiles
-    target_profiles
+    for name in target_profiles {
+        if let Some(&index) = profile_indices.get(name) {
+            profiles[index].lock_state = LockState::new(lock);
+            update(&mut profiles[index])?;
+        }
+    }
+
+    let targets: Vec<_> = target_profiles
         .iter()
         .filter(|name| profile_indices.contains_key(name))
-        .for_each(|name| profiles[profile_indices[name]].is_locked = lock);
+        .map(|name| &profiles[profile_indices[name]])
+        .collect();
+
+    // Cached credentials come back before the files are unlocked, since nothing relocks the
+    // files if this fails afterwards
+    if !lock {
+        let cache = AwsCache::open()?;
+        for profile in &targets {
+            cache.unlock(profile)?;
+        }
+    }
 
     // Write to file
     aws_file.write(&profiles)?;
     aws_file.flush()?;
 
+    // Move cached credentials out of the SDKs' reach
+    if lock {
+        lock_caches(&profiles, &targets, cache_policy)?;
+    }
+
     Ok(profiles)
 }
+
+/// Quarantines or purges cached credentials of `target_profiles`. Cache files shared with a
+/// profile that stays unlocked, such as the token of a common SSO session, are left in place.
+pub fn lock_caches(
+    profiles: &[AwsProfile],
+    target_profiles: &[&AwsProfile],
+    cache_policy: CachePolicy,
+) -> Result<()> {
+    if cache_policy == CachePolicy::Keep {
+        return Ok(());
+    }
+
+    let cache = AwsCache::open()?;
+    let mut in_use = vec![];
+    for profile in profiles {
+        let is_target = target_profiles.iter().any(|p| p.name == profile.name);
+        if !is_target && !profile.lock_state.is_locked() {
+            in_use.extend(cache.cache_files(profile)?);
+        }
+    }
+
+    for profile in target_profiles {
+        let files: Vec<_> = cache
+            .cache_files(profile)?
+            .into_iter()
+            .filter(|file| !in_use.contains(file))
+            .collect();
+        cache.lock(&files, cache_policy)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the tier policies and annotations of `targets` allow `purpose`, then asks
+/// `confirmer` to confirm if any of them not approved by it requires it. Unattended runs cannot
+/// answer, so they fail unless all such targets are approved. Returns the record of the
+/// confirmed targets for the audit log, if any, for the caller to write.
+fn check_tier_policies(
+    targets: &[&AwsProfile],
+    settings: &Settings,
+    purpose: Purpose,
+    checks: &mut UnlockChecks,
+    confirmer: &mut dyn Confirmer,
+) -> Result<Option<AuditRecord>> {
+    // Rules on commands are checked first, so that a denied command asks for nothing
+    let command_line = match purpose {
+        Purpose::Command(command) => command_line(command),
+        Purpose::Until(_) => None,
+    };
+    let mut command_confirmations = vec![];
+    for profile in targets {
+        match settings.check_command(profile, command_line.as_deref()) {
+            CommandVerdict::Allowed => {}
+            CommandVerdict::Confirm(pattern) => command_confirmations.push((*profile, pattern)),
+            CommandVerdict::Denied(reason) => {
+                return Err(Error::CommandDenied {
+                    profile: profile.name.clone(),
+                    command: command_line,
+                    reason,
+                })
+            }
+        }
+    }
+
+    for profile in targets {
+        let tier = profile.effective_tier();
+        let policy = settings.policy(tier);
+        // An annotated limit that cannot be read must not lift the limit
+        let annotated_max_duration =
+            profile
+                .annotations
+                .max_duration()
+                .map_err(|reason| Error::InvalidAnnotation {
+                    profile: profile.name.clone(),
+                    key: annotation::MAX_DURATION.to_string(),
+                    reason,
+                })?;
+        let max_duration = match (policy.max_duration, annotated_max_duration) {
+            (Some(tier_max), Some(max)) => Some(tier_max.min(max)),
+            (tier_max, max) => tier_max.or(max),
+        };
+        if let (Some(max), Some(expires_at)) = (max_duration, purpose.expires_at()) {
+            let duration = expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            if duration > max {
+                return Err(Error::DurationNotAllowed {
+                    profile: profile.name.clone(),
+                    tier,
+                    max,
+                });
+            }
+        }
+
+        if let Some(commands) = &policy.commands {
+            let program = match purpose {
+                Purpose::Command(command) => command.first().map(|program| program_name(program)),
+                Purpose::Until(_) => None,
+            };
+            if !program
+                .as_ref()
+                .is_some_and(|program| commands.contains(program))
+            {
+                return Err(Error::CommandNotAllowed {
+                    profile: profile.name.clone(),
+                    tier,
+                    program,
+                });
+            }
+        }
+    }
+
+    // A pattern can select production profiles the user did not think of, so they are confirmed
+    // even where the tier policy does not ask for it
+    let confirmed_profiles: Vec<_> = targets
+        .iter()
+        .copied()
+        .filter(|profile| {
+            let tier = profile.effective_tier();
+            settings.policy(tier).confirm
+                || (checks.selected_by_pattern && tier >= Tier::Production)
+        })
+        .collect();
+    let unapproved_profiles: Vec<_> = targets
+        .iter()
+        .copied()
+        .filter(|profile| {
+            confirmed_profiles.iter().any(|p| p.name == profile.name)
+                || profile.annotations.confirm_phrase().is_some()
+        })
+        .filter(|profile| !confirmer.is_approved(&profile.name))
+        .collect();
+    // Approving a tier does not approve what runs under it
+    command_confirmations.retain(|(profile, _)| !confirmer.is_command_approved(&profile.name));
+    if !confirmer.is_interactive() {
+        let names: Vec<_> = unapproved_profiles.iter().map(|p| p.name.clone()).collect();
+        if !names.is_empty() {
+            return Err(Error::ConfirmationRequired(names));
+        }
+        let names: Vec<_> = command_confirmations
+            .iter()
+            .map(|(profile, _)| profile.name.clone())
+            .unique()
+            .collect();
+        if !names.is_empty() {
+            return Err(Error::CommandConfirmationRequired(names));
+        }
+    }
+
+    let mut reason = None;
+    let prompted_profiles: Vec<_> = confirmed_profiles
+        .iter()
+        .copied()
+        .filter(|profile| !confirmer.is_approved(&profile.name))
+        .collect();
+    if !prompted_profiles.is_empty() {
+        reason = confirm_unlock(
+            targets,
+            &prompted_profiles,
+            &settings.confirmation,
+            checks.selected_by_pattern,
+            confirmer,
+        )?;
+    }
+
+    for profile in &unapproved_profiles {
+        let Some(phrase) = profile.annotations.confirm_phrase() else {
+            continue;
+        };
+        if confirmer.ask(&format!("Type '{phrase}' to unlock '{}': ", profile.name))? != phrase {
+            return Err(Error::PhraseMismatch(profile.name.clone()));
+        }
+    }
+
+    for (profile, pattern) in &command_confirmations {
+        let message = match &command_line {
+            Some(command_line) => format!(
+                "'{command_line}' matches '{pattern}' for '{}'. Run it? (y/N) ",
+                profile.name
+            ),
+            None => format!(
+                "'{}' will allow commands matching '{pattern}'. Unlock it? (y/N) ",
+                profile.name
+            ),
+        };
+        if !["y", "Y"].contains(&confirmer.ask(&message)?.as_str()) {
+            return Err(declined(&[profile]));
+        }
+    }
+
+    let totp_profiles: Vec<_> = targets
+        .iter()
+        .copied()
+        .filter(|profile| settings.policy(profile.effective_tier()).totp)
+        .collect();
+    if !totp_profiles.is_empty() {
+        verify_totp(&totp_profiles, checks, confirmer)?;
+    }
+
+    if confirmed_profiles.is_empty() {
+        return Ok(None);
+    }
+    let profiles = confirmed_profiles
+        .iter()
+        .map(|p| format!("{} ({})", p.name, p.effective_tier()))
+        .collect();
+
+    Ok(Some(AuditRecord::new(
+        profiles,
+        purpose.to_string(),
+        reason,
+    )))
+}
+
+/// Asks to confirm unlocking `targets` as configured by `confirmation`, where
+/// `confirmed_profiles` are the targets whose tiers require it. Returns the reason given, if
+/// asked for.
+fn confirm_unlock(
+    targets: &[&AwsProfile],
+    confirmed_profiles: &[&AwsProfile],
+    confirmation: &Confirmation,
+    selected_by_pattern: bool,
+    confirmer: &mut dyn Confirmer,
+) -> Result<Option<String>> {
+    // List every target, since groups and aliases may hide what is being unlocked
+    let selected_by = if selected_by_pattern {
+        ", selected by a pattern"
+    } else {
+        ""
+    };
+    let mut intro = format!(
+        "You are unlocking {}{selected_by}.",
+        targets
+            .iter()
+            .map(|p| match p.effective_tier() {
+                Tier::Dev => format!("'{}'", p.name),
+                tier => format!("'{}' ({tier})", p.name),
+            })
+            .format(", ")
+    );
+    if !confirmation.cooldown.is_zero() {
+        let message = format!(
+            "{intro} Input is accepted in {}...",
+            format_duration(confirmation.cooldown)
+        );
+        confirmer.wait(&message, confirmation.cooldown)?;
+        intro.clear();
+    }
+
+    match confirmation.mode {
+        ConfirmMode::Yes => {
+            let answer = confirmer.ask(format!("{intro} Are you sure? (y/N) ").trim_start())?;
+            if !["y", "Y"].contains(&answer.as_str()) {
+                return Err(declined(confirmed_profiles));
+            }
+        }
+        ConfirmMode::Name | ConfirmMode::AccountId => {
+            for profile in confirmed_profiles {
+                let name = profile.name.to_string();
+                let (what, expected) = match account_id(profile) {
+                    Some(account_id) if confirmation.mode == ConfirmMode::AccountId => {
+                        ("account ID", account_id.to_string())
+                    }
+                    _ => ("name", name.clone()),
+                };
+                // The list of targets goes with the first question
+                let intro = std::mem::take(&mut intro);
+                let separator = if intro.is_empty() { "" } else { "\n" };
+                let message =
+                    format!("{intro}{separator}Type the {what} of '{name}' to unlock it: ");
+                if confirmer.ask(&message)? != expected {
+                    return Err(declined(confirmed_profiles));
+                }
+            }
+        }
+    }
+
+    let reason = match confirmation.reason {
+        ReasonPolicy::None => return Ok(None),
+        ReasonPolicy::Optional => confirmer.ask("Reason or ticket ID (optional): ")?,
+        ReasonPolicy::Required => confirmer.ask("Reason or ticket ID: ")?,
+    };
+    if reason.is_empty() && confirmation.reason == ReasonPolicy::Required {
+        return Err(declined(confirmed_profiles));
+    }
+
+    Ok(Some(reason).filter(|reason| !reason.is_empty()))
+}
+
+/// Error for a declined confirmation of `profiles`, telling production apart from the others.
+fn declined(profiles: &[&AwsProfile]) -> Error {
+    if profiles
+        .iter()
+        .any(|profile| profile.effective_tier() >= Tier::Production)
+    {
+        Error::ProductionDeclined
+    } else {
+        Error::ConfirmationDeclined
+    }
+}
+
+/// Asks `confirmer` for a TOTP code, required to unlock `targets`, unless one was already
+/// verified in this run. Approvals do not skip it, as it guards against whatever could pass
+/// them, such as a tampered shell alias.
+fn verify_totp(
+    targets: &[&AwsProfile],
+    checks: &mut UnlockChecks,
+    confirmer: &mut dyn Confirmer,
+) -> Result<()> {
+    if checks.is_totp_verified {
+        return Ok(());
+    }
+
+    let names = targets.iter().map(|p| format!("'{}'", p.name)).join(", ");
+    let mut totp = Totp::load()?.ok_or_else(|| {
+        Error::Totp(format!(
+            "{names} requires a TOTP code, but no secret is enrolled; run `aws-unlock totp enroll`"
+        ))
+    })?;
+    if !confirmer.is_interactive() {
+        return Err(Error::Totp(format!(
+            "{names} requires a TOTP code, but stdin is not a terminal"
+        )));
+    }
+
+    let code = confirmer.ask(&format!("TOTP code to unlock {names}: "))?;
+    if !totp.verify(&code) {
+        return Err(Error::Totp("invalid or already used code".to_string()));
+    }
+    totp.save()?;
+    checks.is_totp_verified = true;
+
+    Ok(())
+}
+
+/// Command line matched by command rules, with the program reduced to its file name, e.g.
+/// `terraform plan -out=plan`.
+fn command_line(command: &[String]) -> Option<String> {
+    let (program, args) = command.split_first()?;
+    Some(
+        std::iter::once(program_name(program))
+            .chain(args.iter().cloned())
+            .join(" "),
+    )
+}
+
+/// File name of a program, so that `/usr/bin/terraform` matches `terraform`.
+fn program_name(program: &str) -> String {
+    Path::new(program).file_name().map_or_else(
+        || program.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        annotation::Annotations,
+        aws_profile::AwsProfileData,
+        confirm::{DenyConfirmer, ScriptedConfirmer},
+        settings::CommandRule,
+    };
+
+    /// Answers nothing, like a run in CI, with the given profiles approved.
+    #[derive(Default)]
+    struct UnattendedConfirmer {
+        approved: Vec<ProfileName>,
+        approved_commands: Vec<ProfileName>,
+    }
+
+    impl Confirmer for UnattendedConfirmer {
+        fn ask(&mut self, message: &str) -> Result<String> {
+            panic!("asked '{message}' without a terminal");
+        }
+
+        fn is_interactive(&self) -> bool {
+            false
+        }
+
+        fn is_approved(&self, profile: &ProfileName) -> bool {
+            self.approved.contains(profile)
+        }
+
+        fn is_command_approved(&self, profile: &ProfileName) -> bool {
+            self.approved_commands.contains(profile)
+        }
+    }
+
+    fn profile(name: &str, tier: Tier, annotations: &[(&str, &str)]) -> AwsProfile {
+        let mut profile_annotations = Annotations::default();
+        for (key, value) in annotations {
+            profile_annotations.insert(key, value);
+        }
+
+        AwsProfile {
+            name: name.into(),
+            tier,
+            rule_match: None,
+            annotations: profile_annotations,
+            lock_state: LockState::Locked,
+            data: AwsProfileData::default(),
+        }
+    }
+
+    /// Checks unlocking `targets` for a minute.
+    fn check(
+        targets: &[&AwsProfile],
+        settings: &Settings,
+        confirmer: &mut dyn Confirmer,
+    ) -> Result<Option<AuditRecord>> {
+        let expires_at = SystemTime::now() + std::time::Duration::from_secs(60);
+        let mut checks = UnlockChecks::default();
+        check_tier_policies(
+            targets,
+            settings,
+            Purpose::Until(expires_at),
+            &mut checks,
+            confirmer,
+        )
+    }
+
+    #[test]
+    fn dev_profiles_are_not_confirmed() {
+        let dev = profile("dev", Tier::Dev, &[]);
+        let mut confirmer = ScriptedConfirmer::new(Vec::<String>::new());
+        let record = check(&[&dev], &Settings::default(), &mut confirmer).unwrap();
+        assert!(confirmer.messages.is_empty());
+        assert!(record.is_none());
+    }
+
+    #[test]
+    fn production_profiles_are_confirmed() {
+        let dev = profile("dev", Tier::Dev, &[]);
+        let prod = profile("prod", Tier::Production, &[]);
+        let mut confirmer = ScriptedConfirmer::new(["y"]);
+        check(&[&dev, &prod], &Settings::default(), &mut confirmer).unwrap();
+        assert_eq!(
+            confirmer.messages,
+            ["You are unlocking 'dev', 'prod' (production). Are you sure? (y/N) "]
+        );
+    }
+
+    #[test]
+    fn declined_confirmation_fails() {
+        let prod = profile("prod", Tier::Production, &[]);
+        for answer in ["n", ""] {
+            let mut confirmer = ScriptedConfirmer::new([answer]);
+            let res = check(&[&prod], &Settings::default(), &mut confirmer);
+            assert!(matches!(res, Err(Error::ProductionDeclined)));
+        }
+
+        let res = check(&[&prod], &Settings::default(), &mut DenyConfirmer);
+        assert!(matches!(res, Err(Error::ProductionDeclined)));
+    }
+
+    #[test]
+    fn confirm_phrase_must_match() {
+        let dev = profile("dev", Tier::Dev, &[(annotation::CONFIRM, "i-am-sure")]);
+
+        let mut confirmer = ScriptedConfirmer::new(["y"]);
+        let res = check(&[&dev], &Settings::default(), &mut confirmer);
+        assert!(matches!(res, Err(Error::PhraseMismatch(name)) if name == dev.name));
+
+        let mut confirmer = ScriptedConfirmer::new(["i-am-sure"]);
+        check(&[&dev], &Settings::default(), &mut confirmer).unwrap();
+    }
+
+    #[test]
+    fn unattended_runs_fail_closed() {
+        let prod = profile("prod", Tier::Production, &[]);
+        let phrase = profile("phrase", Tier::Dev, &[(annotation::CONFIRM, "yes")]);
+        let res = check(
+            &[&prod, &phrase],
+            &Settings::default(),
+            &mut UnattendedConfirmer::default(),
+        );
+        assert!(matches!(
+            res,
+            Err(Error::ConfirmationRequired(names)) if names == [prod.name, phrase.name]
+        ));
+    }
+
+    #[test]
+    fn approved_profiles_are_not_asked() {
+        let prod = profile("prod", Tier::Production, &[]);
+        let phrase = profile("phrase", Tier::Dev, &[(annotation::CONFIRM, "yes")]);
+        let mut confirmer = UnattendedConfirmer {
+            approved: vec![prod.name.clone(), phrase.name.clone()],
+            ..Default::default()
+        };
+        check(&[&prod, &phrase], &Settings::default(), &mut confirmer).unwrap();
+    }
+
+    fn confirm_terraform_apply(tier: Tier) -> Settings {
+        let mut settings = Settings::default();
+        settings.command_rules.push(CommandRule {
+            tier: Some(tier),
+            confirm: vec![glob::Pattern::new("terraform apply*").unwrap()],
+            ..Default::default()
+        });
+        settings
+    }
+
+    #[test]
+    fn approvals_do_not_approve_commands() {
+        let settings = confirm_terraform_apply(Tier::Production);
+        // A rule on a tier also covers the stricter ones
+        let bg = profile("bg", Tier::BreakGlass, &[]);
+        let command = ["terraform".to_string(), "apply".to_string()];
+        let purpose = Purpose::Command(&command);
+
+        let mut confirmer = UnattendedConfirmer {
+            approved: vec![bg.name.clone()],
+            ..Default::default()
+        };
+        let mut checks = UnlockChecks::default();
+        let res = check_tier_policies(&[&bg], &settings, purpose, &mut checks, &mut confirmer);
+        assert!(matches!(res, Err(Error::CommandConfirmationRequired(_))));
+
+        confirmer.approved_commands.push(bg.name.clone());
+        check_tier_policies(&[&bg], &settings, purpose, &mut checks, &mut confirmer).unwrap();
+    }
+
+    #[test]
+    fn declined_commands_below_production_are_not_production_declines() {
+        let settings = confirm_terraform_apply(Tier::Dev);
+        let dev = profile("dev", Tier::Dev, &[]);
+        let command = ["terraform".to_string(), "apply".to_string()];
+
+        let mut confirmer = ScriptedConfirmer::new(["n"]);
+        let res = check_tier_policies(
+            &[&dev],
+            &settings,
+            Purpose::Command(&command),
+            &mut UnlockChecks::default(),
+            &mut confirmer,
+        );
+        assert!(matches!(res, Err(Error::ConfirmationDeclined)));
+        assert_eq!(
+            confirmer.messages,
+            ["'terraform apply' matches 'terraform apply*' for 'dev'. Run it? (y/N) "]
+        );
+    }
+
+    #[test]
+    fn pattern_selections_are_noted() {
+        let prod = profile("prod", Tier::Production, &[]);
+        let mut confirmer = ScriptedConfirmer::new(["y"]);
+        let expires_at = SystemTime::now() + std::time::Duration::from_secs(60);
+        let mut checks = UnlockChecks {
+            selected_by_pattern: true,
+            ..Default::default()
+        };
+        check_tier_policies(
+            &[&prod],
+            &Settings::default(),
+            Purpose::Until(expires_at),
+            &mut checks,
+            &mut confirmer,
+        )
+        .unwrap();
+        assert!(confirmer.messages[0].contains("selected by a pattern"));
+    }
+
+    #[test]
+    fn pattern_selections_are_confirmed_regardless_of_policy() {
+        let settings = Settings::from_user_file("[tiers.production]\nconfirm = false\n");
+        let prod = profile("prod", Tier::Production, &[]);
+        let expires_at = SystemTime::now() + std::time::Duration::from_secs(60);
+
+        let mut confirmer = ScriptedConfirmer::new(Vec::<String>::new());
+        check(&[&prod], &settings, &mut confirmer).unwrap();
+        assert!(confirmer.messages.is_empty());
+
+        let mut checks = UnlockChecks {
+            selected_by_pattern: true,
+            ..Default::default()
+        };
+        let res = check_tier_policies(
+            &[&prod],
+            &settings,
+            Purpose::Until(expires_at),
+            &mut checks,
+            &mut DenyConfirmer,
+        );
+        assert!(matches!(res, Err(Error::ProductionDeclined)));
+    }
+
+    #[test]
+    fn name_mode_asks_for_each_name() {
+        let mut settings = Settings::default();
+        settings.confirmation.mode = ConfirmMode::Name;
+        let prod = profile("prod", Tier::Production, &[]);
+        let bg = profile("bg", Tier::BreakGlass, &[]);
+
+        let mut confirmer = ScriptedConfirmer::new(["prod", "bg"]);
+        check(&[&prod, &bg], &settings, &mut confirmer).unwrap();
+        assert_eq!(confirmer.messages.len(), 2);
+        assert!(confirmer.messages[1].starts_with("Type the name of 'bg'"));
+
+        let mut confirmer = ScriptedConfirmer::new(["prod", "prod"]);
+        let res = check(&[&prod, &bg], &settings, &mut confirmer);
+        assert!(matches!(res, Err(Error::ProductionDeclined)));
+    }
+
+    #[test]
+    fn account_id_mode_asks_for_the_account_id() {
+        let mut settings = Settings::default();
+        settings.confirmation.mode = ConfirmMode::AccountId;
+        let prod = profile(
+            "prod",
+            Tier::Production,
+            &[(annotation::ACCOUNT_ID, "123456789012")],
+        );
+        let other = profile("other", Tier::Production, &[]);
+
+        let mut confirmer = ScriptedConfirmer::new(["123456789012", "other"]);
+        check(&[&prod, &other], &settings, &mut confirmer).unwrap();
+        assert!(confirmer.messages[0].contains("Type the account ID of 'prod'"));
+        // Profiles without a known account ID fall back to their name
+        assert!(confirmer.messages[1].starts_with("Type the name of 'other'"));
+
+        let mut confirmer = ScriptedConfirmer::new(["prod"]);
+        let res = check(&[&prod], &settings, &mut confirmer);
+        assert!(matches!(res, Err(Error::ProductionDeclined)));
+    }
+
+    #[test]
+    fn required_reason_must_be_given_and_is_recorded() {
+        let mut settings = Settings::default();
+        settings.confirmation.reason = ReasonPolicy::Required;
+        let dev = profile("dev", Tier::Dev, &[]);
+        let prod = profile("prod", Tier::Production, &[]);
+
+        let mut confirmer = ScriptedConfirmer::new(["y", ""]);
+        let res = check(&[&prod], &settings, &mut confirmer);
+        assert!(matches!(res, Err(Error::ProductionDeclined)));
+
+        let mut confirmer = ScriptedConfirmer::new(["y", "TICKET-1"]);
+        let record = check(&[&dev, &prod], &settings, &mut confirmer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(confirmer.messages[1], "Reason or ticket ID: ");
+        // Only the confirmed profiles are recorded
+        assert_eq!(record.profiles, ["prod (production)"]);
+        assert_eq!(record.reason.as_deref(), Some("TICKET-1"));
+    }
+}
@@ -1,13 +1,54 @@
-use anyhow::{anyhow, bail, Result};
 use home::home_dir;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     fmt,
     fs::{File, OpenOptions},
     io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
-use crate::{line_lexer::EntryLineLexer, line_parser::EntryLineParser};
+use crate::{
+    annotation::Annotations,
+    backup::backup_file,
+    error::Result,
+    line_lexer::{is_locked_line, strip_locked_comment, EntryLineLexer},
+    line_parser::{Entry, EntryLineParser, LockState, RawOption, Spelling},
+    parse_error::{ParseError, ParseErrorKind},
+    secret::Secret,
+    settings::{RuleMatch, Settings},
+    tier::Tier,
+};
+
+/// Keyword of `[sso-session NAME]` sections in ~/.aws/config.
+pub const SSO_SESSION_KEYWORD: &str = "sso-session";
+
+/// Options of profiles in ~/.aws/config with a field in [`AwsConfigData`].
+const CONFIG_KEYS: [&str; 10] = [
+    "region",
+    "output",
+    "role_arn",
+    "source_profile",
+    "sso_session",
+    "sso_start_url",
+    "sso_region",
+    "sso_account_id",
+    "sso_role_name",
+    "credential_process",
+];
+
+/// Options in ~/.aws/credentials with a field in [`AwsCredentialData`].
+const CREDENTIALS_KEYS: [&str; 6] = [
+    "aws_access_key_id",
+    "aws_secret_access_key",
+    "aws_session_token",
+    "aws_session_expiration",
+    "aws_security_token",
+    "region",
+];
+
+/// Keywords of the sections in ~/.aws/config other than profiles.
+const SECTION_KEYWORDS: [&str; 2] = [SSO_SESSION_KEYWORD, "services"];
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ProfileName {
@@ -40,8 +81,15 @@ impl fmt::Display for ProfileName {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct WithAwsProfileMetadata<T> {
     pub name: ProfileName,
-    pub is_production: bool,
-    pub is_locked: bool,
+
+    /// Tier set by the marker in the files. See [`WithAwsProfileMetadata::effective_tier`].
+    pub tier: Tier,
+
+    /// Production rule in the settings the profile matches, if any. Never written to the files.
+    pub rule_match: Option<RuleMatch>,
+
+    pub annotations: Annotations,
+    pub lock_state: LockState,
     pub data: T,
 }
 
@@ -49,20 +97,31 @@ impl<T> WithAwsProfileMetadata<T> {
     pub fn to_ref(&self) -> WithAwsProfileMetadata<&T> {
         WithAwsProfileMetadata {
             name: self.name.clone(),
-            is_production: self.is_production,
-            is_locked: self.is_locked,
+            tier: self.tier,
+            rule_match: self.rule_match.clone(),
+            annotations: self.annotations.clone(),
+            lock_state: self.lock_state,
             data: &self.data,
         }
     }
 
+    /// The more sensitive of the marked tier and the tier of the matched rule.
+    pub fn effective_tier(&self) -> Tier {
+        self.rule_match
+            .as_ref()
+            .map_or(self.tier, |rule_match| rule_match.tier.max(self.tier))
+    }
+
     pub fn map<U, F>(self, f: F) -> WithAwsProfileMetadata<U>
     where
         F: FnOnce(T) -> U,
     {
         WithAwsProfileMetadata {
             name: self.name,
-            is_production: self.is_production,
-            is_locked: self.is_locked,
+            tier: self.tier,
+            rule_match: self.rule_match,
+            annotations: self.annotations,
+            lock_state: self.lock_state,
             data: f(self.data),
         }
     }
@@ -71,7 +130,10 @@ impl<T> WithAwsProfileMetadata<T> {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub struct AwsProfileData {
     pub conf: AwsConfigData,
-    pub cred: AwsCredentialData,
+
+    /// Credentials of the profile. `None` for profiles only defined in ~/.aws/config, such as
+    /// SSO or assume-role profiles.
+    pub cred: Option<AwsCredentialData>,
 }
 
 pub type AwsProfile = WithAwsProfileMetadata<AwsProfileData>;
@@ -86,11 +148,88 @@ pub struct AwsConfigData {
 
     /// `output` in ~/.aws/config.
     pub output: Option<String>,
+
+    /// `role_arn` in ~/.aws/config.
+    pub role_arn: Option<String>,
+
+    /// `source_profile` in ~/.aws/config.
+    pub source_profile: Option<String>,
+
+    /// `sso_session` in ~/.aws/config.
+    pub sso_session: Option<String>,
+
+    /// `sso_start_url` of the `[sso-session]` section named by `sso_session`. Never written to
+    /// the profile.
+    pub sso_session_start_url: Option<String>,
+
+    /// `sso_start_url` in ~/.aws/config.
+    pub sso_start_url: Option<String>,
+
+    /// `sso_region` in ~/.aws/config.
+    pub sso_region: Option<String>,
+
+    /// `sso_account_id` in ~/.aws/config.
+    pub sso_account_id: Option<String>,
+
+    /// `sso_role_name` in ~/.aws/config.
+    pub sso_role_name: Option<String>,
+
+    /// `credential_process` in ~/.aws/config.
+    pub credential_process: Option<String>,
+
+    /// Other options in ~/.aws/config, such as `mfa_serial`, as they are written.
+    pub other_options: Vec<RawOption>,
+
+    /// How the section is written in ~/.aws/config.
+    pub spelling: Spelling,
 }
 
 pub type AwsConfig = WithAwsProfileMetadata<AwsConfigData>;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+impl AwsConfigData {
+    /// `sso_start_url` of the profile, or of its `[sso-session]` section.
+    pub fn resolved_sso_start_url(&self) -> Option<&str> {
+        self.sso_start_url
+            .as_deref()
+            .or(self.sso_session_start_url.as_deref())
+    }
+}
+
+/// A section of ~/.aws/config other than a profile, such as `[sso-session NAME]`. aws-unlock
+/// neither locks nor unlocks it, and writes it back as it was read.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConfigSection {
+    pub comments: Vec<String>,
+    pub tier: Tier,
+    pub annotations: Annotations,
+
+    /// Whether the header is commented out.
+    pub is_locked: bool,
+
+    /// Options as they are written.
+    pub options: Vec<RawOption>,
+
+    pub spelling: Spelling,
+}
+
+impl ConfigSection {
+    /// Name of the section if it is `[sso-session NAME]`.
+    pub fn sso_session_name(&self) -> Option<&str> {
+        let (keyword, name) = config_section_name(self.spelling.header.as_deref()?)?;
+        (keyword == SSO_SESSION_KEYWORD).then_some(name)
+    }
+
+    /// Value of the last option with the lowercase key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|option| option.key == key)
+            .map(|option| option.value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
 pub struct AwsCredentialData {
     /// Comment lines in ~/.aws/credentials.
     pub comments: Vec<String>,
@@ -99,49 +238,114 @@ pub struct AwsCredentialData {
     pub aws_access_key_id: String,
 
     /// `aws_secret_access_key` in ~/.aws/credentials.
-    pub aws_secret_access_key: String,
+    pub aws_secret_access_key: Secret,
 
     /// `aws_session_token` in ~/.aws/credentials.
-    pub aws_session_token: Option<String>,
+    pub aws_session_token: Option<Secret>,
 
     /// `aws_session_expiration` in ~/.aws/credentials.
     pub aws_session_expiration: Option<String>,
 
     /// `aws_security_token` in ~/.aws/credentials.
-    pub aws_security_token: Option<String>,
+    pub aws_security_token: Option<Secret>,
 
     /// `region` in ~/.aws/credentials.
     pub region: Option<String>,
+
+    /// Other options in ~/.aws/credentials, as they are written.
+    #[serde(default)]
+    pub other_options: Vec<RawOption>,
+
+    /// How the section is written in ~/.aws/credentials.
+    #[serde(default)]
+    pub spelling: Spelling,
 }
 
 pub type AwsCredential = WithAwsProfileMetadata<AwsCredentialData>;
 
+/// What to do with a profile defined more than once in the same file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Refuse to read the file.
+    #[default]
+    Error,
+
+    /// Combine the sections into one. Options of later sections take precedence.
+    Merge,
+
+    /// Keep the first section and drop the others.
+    KeepFirst,
+
+    /// Keep the last section and drop the others.
+    KeepLast,
+}
+
 #[derive(Debug)]
 pub struct AwsFile {
+    config_path: PathBuf,
     config: File,
+    credentials_path: PathBuf,
     credentials: File,
+
+    /// Whether the files use CRLF line endings, which are kept on write.
+    config_crlf: bool,
+    credentials_crlf: bool,
+
+    on_duplicate: DuplicatePolicy,
+    settings: Settings,
+
+    /// Whether duplicate sections were merged or dropped on parse. The original files are
+    /// backed up before they are overwritten.
+    has_resolved_duplicates: bool,
+
+    /// Sections of ~/.aws/config other than profiles, written back after the profiles.
+    sections: Vec<ConfigSection>,
 }
 
 impl AwsFile {
     pub fn open() -> Result<AwsFile> {
-        let home_dir = home_dir().expect("failed to locate home directory");
+        let (config_path, credentials_path) = aws_file_paths();
+        AwsFile::open_at(config_path, credentials_path, Settings::load()?)
+    }
 
-        let aws_home = home_dir.join(".aws");
+    fn open_at(
+        config_path: PathBuf,
+        credentials_path: PathBuf,
+        settings: Settings,
+    ) -> Result<AwsFile> {
         let config = OpenOptions::new()
             .read(true)
             .write(true)
-            .open(aws_home.join("config"))?;
+            .open(&config_path)?;
         let credentials = OpenOptions::new()
             .read(true)
             .write(true)
-            .open(aws_home.join("credentials"))?;
+            .open(&credentials_path)?;
 
         Ok(AwsFile {
+            config_path,
             config,
+            credentials_path,
             credentials,
+            config_crlf: false,
+            credentials_crlf: false,
+            on_duplicate: DuplicatePolicy::Error,
+            settings,
+            has_resolved_duplicates: false,
+            sections: vec![],
         })
     }
 
+    pub fn with_duplicate_policy(mut self, on_duplicate: DuplicatePolicy) -> Self {
+        self.on_duplicate = on_duplicate;
+        self
+    }
+
+    /// Settings loaded when the files were opened.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         self.config.flush()?;
         self.credentials.flush()?;
@@ -175,59 +379,113 @@ impl AwsFile {
         names
             .into_iter()
             .map(|name| {
-                let (conf_is_production, conf_is_locked, conf_data) = config
-                    .remove(name)
-                    .map(|conf| (conf.is_production, conf.is_locked, conf.data))
-                    .unwrap_or_default();
-                let (cred_is_production, cred_is_locked, cred_data) = credentials
-                    .remove(name)
-                    .map(|cred| (cred.is_production, cred.is_locked, cred.data))
-                    .ok_or_else(|| anyhow!("credentials '{name}' not found",))?;
-
-                Ok(AwsProfile {
-                    is_production: conf_is_production || cred_is_production,
-                    is_locked: conf_is_locked || cred_is_locked,
+                let conf = config.remove(name);
+                let cred = credentials.remove(name);
+                let conf_tier = conf.as_ref().map_or(Tier::Dev, |conf| conf.tier);
+                let cred_tier = cred.as_ref().map_or(Tier::Dev, |cred| cred.tier);
+                let conf_lock_state = conf.as_ref().map(|conf| conf.lock_state);
+                let cred_lock_state = cred.as_ref().map(|cred| cred.lock_state);
+
+                // Annotations in ~/.aws/config take precedence
+                let mut annotations = Annotations::default();
+                let cred_data = cred.map(|cred| {
+                    annotations.merge(cred.annotations);
+                    cred.data
+                });
+                let conf_data = conf.map_or_else(Default::default, |conf| {
+                    annotations.merge(conf.annotations);
+                    conf.data
+                });
+
+                // A profile locked in one file but not in the other is inconsistent as well
+                let lock_state = match (conf_lock_state, cred_lock_state) {
+                    (Some(conf), Some(cred)) if conf != cred => LockState::Inconsistent,
+                    (Some(state), _) | (None, Some(state)) => state,
+                    (None, None) => unreachable!("profile is found in either file"),
+                };
+
+                let mut profile = AwsProfile {
+                    tier: conf_tier.max(cred_tier),
+                    rule_match: None,
+                    annotations,
+                    lock_state,
                     name: name.clone(),
                     data: AwsProfileData {
                         conf: conf_data,
                         cred: cred_data,
                     },
-                })
+                };
+                profile.rule_match = self.settings.match_rules(&profile);
+
+                Ok(profile)
             })
             .collect()
     }
 
     fn parse_config(&mut self) -> Result<Vec<AwsConfig>> {
-        let mut buf = String::new();
-        self.config.seek(SeekFrom::Start(0))?;
-        self.config.read_to_string(&mut buf)?;
-        let lexer = &mut EntryLineLexer::new(&buf);
-        let lines = lexer.tokenize()?;
-        let entries = EntryLineParser::new(lines).parse()?;
-
-        entries
-            .into_iter()
-            .map(|entry| {
-                let name = if entry.header == "default" {
-                    ProfileName::Default
-                } else {
-                    let ["profile", name] = *entry.header.splitn(2, ' ').collect::<Vec<_>>() else {
-                        bail!("unexpected header in your config: {:?}", entry.header);
-                    };
-
-                    name.into()
+        let (buf, entries) = read_entries(&mut self.config, &self.config_path)?;
+        self.config_crlf = buf.contains("\r\n");
+        let mut sections = vec![];
+        self.sections.clear();
+        for entry in entries {
+            if let Some(name) = config_profile_name(&entry.header) {
+                sections.push((name, entry));
+            } else if config_section_name(&entry.header).is_some() {
+                self.sections.push(ConfigSection {
+                    options: entry.options,
+                    comments: entry.comments,
+                    tier: entry.tier,
+                    annotations: entry.annotations,
+                    is_locked: entry.is_locked,
+                    spelling: entry.spelling,
+                });
+            } else {
+                let kind = ParseErrorKind::UnknownHeader {
+                    header: entry.header.clone(),
                 };
+                return Err(ParseError::new(kind, Some(entry.header_span))
+                    .with_source(&self.config_path, &buf)
+                    .into());
+            }
+        }
+        let (sections, has_duplicates) =
+            resolve_duplicates(sections, self.on_duplicate, &self.config_path, &buf)?;
+        self.has_resolved_duplicates |= has_duplicates;
 
-                let region = entry.values.get("region").cloned();
-                let output = entry.values.get("output").cloned();
+        sections
+            .into_iter()
+            .map(|(name, entry)| {
+                let get_optional = |key| entry.values.get(key).cloned();
+                let other_options = other_options(&entry, &CONFIG_KEYS);
+                let sso_session = get_optional("sso_session");
+                let sso_session_start_url = sso_session.as_deref().and_then(|session_name| {
+                    let section = self
+                        .sections
+                        .iter()
+                        .find(|section| section.sso_session_name() == Some(session_name))?;
+                    section.get("sso_start_url").map(str::to_string)
+                });
                 Ok(AwsConfig {
                     name,
-                    is_production: entry.is_production,
-                    is_locked: entry.is_locked,
+                    tier: entry.tier,
+                    rule_match: None,
+                    annotations: entry.annotations.clone(),
+                    lock_state: entry.lock_state(),
                     data: AwsConfigData {
                         comments: entry.comments,
-                        region,
-                        output,
+                        region: get_optional("region"),
+                        output: get_optional("output"),
+                        role_arn: get_optional("role_arn"),
+                        source_profile: get_optional("source_profile"),
+                        sso_session,
+                        sso_session_start_url,
+                        sso_start_url: get_optional("sso_start_url"),
+                        sso_region: get_optional("sso_region"),
+                        sso_account_id: get_optional("sso_account_id"),
+                        sso_role_name: get_optional("sso_role_name"),
+                        credential_process: get_optional("credential_process"),
+                        other_options,
+                        spelling: entry.spelling.clone(),
                     },
                 })
             })
@@ -235,36 +493,45 @@ impl AwsFile {
     }
 
     fn parse_credentials(&mut self) -> Result<Vec<AwsCredential>> {
-        let mut buf = String::new();
-        self.config.seek(SeekFrom::Start(0))?;
-        self.credentials.read_to_string(&mut buf)?;
-        let lexer = &mut EntryLineLexer::new(&buf);
-        let lines = lexer.tokenize()?;
-        let entries = EntryLineParser::new(lines).parse()?;
-
-        entries
+        let (buf, entries) = read_entries(&mut self.credentials, &self.credentials_path)?;
+        self.credentials_crlf = buf.contains("\r\n");
+        let sections = entries
             .into_iter()
-            .map(|entry| {
-                let name = entry.header.into();
-                let get_required = |key| {
-                    entry
-                        .values
-                        .get(key)
-                        .ok_or_else(|| anyhow!("missing key '{key}' in '{name}' credentials"))
+            .map(|entry| (credentials_profile_name(&entry.header), entry))
+            .collect();
+        let (sections, has_duplicates) =
+            resolve_duplicates(sections, self.on_duplicate, &self.credentials_path, &buf)?;
+        self.has_resolved_duplicates |= has_duplicates;
+
+        sections
+            .into_iter()
+            .map(|(name, entry)| {
+                let get_required = |key: &str| {
+                    entry.values.get(key).ok_or_else(|| {
+                        let kind = ParseErrorKind::MissingKey {
+                            profile: name.to_string(),
+                            key: key.to_string(),
+                        };
+                        ParseError::new(kind, Some(entry.header_span))
+                            .with_source(&self.credentials_path, &buf)
+                    })
                 };
                 let get_optional = |key| entry.values.get(key);
 
                 let aws_access_key_id = get_required("aws_access_key_id")?.clone();
-                let aws_secret_access_key = get_required("aws_secret_access_key")?.clone();
-                let aws_session_token = get_optional("aws_session_token").cloned();
+                let aws_secret_access_key = Secret::new(get_required("aws_secret_access_key")?);
+                let aws_session_token = get_optional("aws_session_token").map(Secret::new);
                 let aws_session_expiration = get_optional("aws_session_expiration").cloned();
-                let aws_security_token = get_optional("aws_security_token").cloned();
+                let aws_security_token = get_optional("aws_security_token").map(Secret::new);
                 let region = get_optional("region").cloned();
+                let other_options = other_options(&entry, &CREDENTIALS_KEYS);
 
                 Ok(AwsCredential {
                     name,
-                    is_production: entry.is_production,
-                    is_locked: entry.is_locked,
+                    tier: entry.tier,
+                    rule_match: None,
+                    annotations: entry.annotations.clone(),
+                    lock_state: entry.lock_state(),
                     data: AwsCredentialData {
                         comments: entry.comments,
                         aws_access_key_id,
@@ -273,20 +540,39 @@ impl AwsFile {
                         aws_session_expiration,
                         aws_security_token,
                         region,
+                        other_options,
+                        spelling: entry.spelling.clone(),
                     },
                 })
             })
             .collect()
     }
 
+    /// Returns true if duplicate sections were merged or dropped on parse.
+    pub fn has_resolved_duplicates(&self) -> bool {
+        self.has_resolved_duplicates
+    }
+
+    /// Overwrites the files with the profiles. If duplicate sections were dropped or merged on
+    /// parse, the original files are backed up first.
     pub fn write(&mut self, profiles: &[AwsProfile]) -> Result<()> {
+        if self.has_resolved_duplicates {
+            let retention = self.settings.backup_retention;
+            backup_file(&self.config_path, retention)?;
+            backup_file(&self.credentials_path, retention)?;
+            self.has_resolved_duplicates = false;
+        }
+
         let config: Vec<_> = profiles
             .iter()
             .map(|profile| profile.to_ref().map(|data| data.conf.clone()))
             .collect();
         let credentials: Vec<_> = profiles
             .iter()
-            .map(|profile| profile.to_ref().map(|data| data.cred.clone()))
+            .filter_map(|profile| {
+                let cred = profile.data.cred.clone()?;
+                Some(profile.to_ref().map(|_| cred))
+            })
             .collect();
         self.write_config(&config)?;
         self.write_credentials(&credentials)?;
@@ -295,77 +581,142 @@ impl AwsFile {
     }
 
     fn write_config(&mut self, config: &[AwsConfig]) -> Result<()> {
-        self.config.seek(SeekFrom::Start(0))?;
-        self.config.set_len(0)?;
+        let mut out = vec![];
 
         let mut first = true;
         for conf in config {
             if !first {
-                writeln!(self.config)?;
+                writeln!(out)?;
             }
             first = false;
 
-            for comment in &conf.data.comments {
-                writeln!(self.config, "# {}", comment)?;
-            }
+            write_comments(&mut out, &conf.data.comments)?;
 
-            if conf.is_production {
-                writeln!(self.config, "# production")?;
+            if let Some(marker) = conf.tier.marker() {
+                writeln!(out, "{marker}")?;
+            }
+            for (key, value) in conf.annotations.iter() {
+                writeln!(out, "# aws-unlock: {key}={value}")?;
             }
 
-            let locked_prefix = if conf.is_locked { "# " } else { "" };
+            // Inconsistent sections are written fully locked, never unlocked
+            let locked_prefix = if conf.lock_state == LockState::Unlocked {
+                ""
+            } else {
+                "# "
+            };
 
-            match &conf.name {
-                ProfileName::Named(name) => {
-                    writeln!(self.config, "{}[profile {}]", locked_prefix, name)?
+            let spelling = &conf.data.spelling;
+            let header = spelling
+                .header
+                .as_ref()
+                .filter(|header| config_profile_name(header).as_ref() == Some(&conf.name));
+            match (header, &conf.name) {
+                (Some(header), _) => writeln!(out, "{}[{}]", locked_prefix, header)?,
+                (None, ProfileName::Named(name)) => {
+                    writeln!(out, "{}[profile {}]", locked_prefix, name)?
                 }
-                ProfileName::Default => writeln!(self.config, "{}[default]", locked_prefix)?,
+                (None, ProfileName::Default) => writeln!(out, "{}[default]", locked_prefix)?,
             }
 
-            let mut write = |key: &str, value: Option<&str>| -> Result<()> {
-                if let Some(value) = value {
-                    writeln!(self.config, "{}{} = {}", locked_prefix, key, value)?;
-                }
-
-                Ok(())
+            let mut write = |key: &str, value: Option<&str>| {
+                write_option(&mut out, spelling, locked_prefix, key, value)
             };
 
-            let AwsConfigData { region, output, .. } = &conf.data;
+            let AwsConfigData {
+                region,
+                output,
+                role_arn,
+                source_profile,
+                sso_session,
+                sso_start_url,
+                sso_region,
+                sso_account_id,
+                sso_role_name,
+                credential_process,
+                other_options,
+                ..
+            } = &conf.data;
             write("region", region.as_deref())?;
             write("output", output.as_deref())?;
+            write("role_arn", role_arn.as_deref())?;
+            write("source_profile", source_profile.as_deref())?;
+            write("sso_session", sso_session.as_deref())?;
+            write("sso_start_url", sso_start_url.as_deref())?;
+            write("sso_region", sso_region.as_deref())?;
+            write("sso_account_id", sso_account_id.as_deref())?;
+            write("sso_role_name", sso_role_name.as_deref())?;
+            write("credential_process", credential_process.as_deref())?;
+            for option in other_options {
+                write_raw_option(&mut out, locked_prefix, option)?;
+            }
+            write_section_comments(&mut out, &spelling.trailing_comments, locked_prefix)?;
         }
 
-        Ok(())
+        for section in &self.sections {
+            if !first {
+                writeln!(out)?;
+            }
+            first = false;
+
+            write_comments(&mut out, &section.comments)?;
+            if let Some(marker) = section.tier.marker() {
+                writeln!(out, "{marker}")?;
+            }
+            for (key, value) in section.annotations.iter() {
+                writeln!(out, "# aws-unlock: {key}={value}")?;
+            }
+
+            let locked_prefix = if section.is_locked { "# " } else { "" };
+            let spelling = &section.spelling;
+            let header = spelling.header.as_deref().unwrap_or_default();
+            writeln!(out, "{}[{}]", locked_prefix, header)?;
+            for option in &section.options {
+                write_raw_option(&mut out, locked_prefix, option)?;
+            }
+            write_section_comments(&mut out, &spelling.trailing_comments, locked_prefix)?;
+        }
+
+        overwrite(&mut self.config, &out, self.config_crlf)
     }
 
     fn write_credentials(&mut self, credentials: &[AwsCredential]) -> Result<()> {
-        self.credentials.seek(SeekFrom::Start(0))?;
-        self.credentials.set_len(0)?;
+        let mut out = vec![];
 
         let mut first = true;
         for cred in credentials {
             if !first {
-                writeln!(self.credentials)?;
+                writeln!(out)?;
             }
             first = false;
 
-            for comment in &cred.data.comments {
-                writeln!(self.credentials, "# {}", comment)?;
-            }
+            write_comments(&mut out, &cred.data.comments)?;
 
-            if cred.is_production {
-                writeln!(self.credentials, "# production")?;
+            if let Some(marker) = cred.tier.marker() {
+                writeln!(out, "{marker}")?;
+            }
+            for (key, value) in cred.annotations.iter() {
+                writeln!(out, "# aws-unlock: {key}={value}")?;
             }
 
-            let locked_prefix = if cred.is_locked { "# " } else { "" };
-            writeln!(self.credentials, "{}[{}]", locked_prefix, cred.name)?;
+            let locked_prefix = if cred.lock_state == LockState::Unlocked {
+                ""
+            } else {
+                "# "
+            };
 
-            let mut write = |key: &str, value: Option<&str>| -> Result<()> {
-                if let Some(value) = value {
-                    writeln!(self.credentials, "{}{} = {}", locked_prefix, key, value)?;
-                }
+            let spelling = &cred.data.spelling;
+            let header = spelling
+                .header
+                .as_ref()
+                .filter(|header| credentials_profile_name(header) == cred.name);
+            match header {
+                Some(header) => writeln!(out, "{}[{}]", locked_prefix, header)?,
+                None => writeln!(out, "{}[{}]", locked_prefix, cred.name)?,
+            }
 
-                Ok(())
+            let mut write = |key: &str, value: Option<&str>| {
+                write_option(&mut out, spelling, locked_prefix, key, value)
             };
 
             let AwsCredentialData {
@@ -375,17 +726,451 @@ impl AwsFile {
                 aws_session_expiration,
                 aws_security_token,
                 region,
+                other_options,
                 ..
             } = &cred.data;
 
             write("aws_access_key_id", Some(aws_access_key_id))?;
-            write("aws_secret_access_key", Some(aws_secret_access_key))?;
-            write("aws_session_token", aws_session_token.as_deref())?;
+            write(
+                "aws_secret_access_key",
+                Some(aws_secret_access_key.expose()),
+            )?;
+            write(
+                "aws_session_token",
+                aws_session_token.as_ref().map(Secret::expose),
+            )?;
             write("aws_session_expiration", aws_session_expiration.as_deref())?;
-            write("aws_security_token", aws_security_token.as_deref())?;
+            write(
+                "aws_security_token",
+                aws_security_token.as_ref().map(Secret::expose),
+            )?;
             write("region", region.as_deref())?;
+            for option in other_options {
+                write_raw_option(&mut out, locked_prefix, option)?;
+            }
+            write_section_comments(&mut out, &spelling.trailing_comments, locked_prefix)?;
         }
 
-        Ok(())
+        overwrite(&mut self.credentials, &out, self.credentials_crlf)
+    }
+}
+
+/// Returns the paths of ~/.aws/config and ~/.aws/credentials.
+pub fn aws_file_paths() -> (PathBuf, PathBuf) {
+    let home_dir = home_dir().expect("failed to locate home directory");
+    let aws_home = home_dir.join(".aws");
+
+    (aws_home.join("config"), aws_home.join("credentials"))
+}
+
+/// Returns the profile name of a section header in ~/.aws/config, or `None` if the header is
+/// neither `[default]` nor `[profile <name>]`.
+/// Whitespace around and within the header and the case of `profile` do not matter.
+pub fn config_profile_name(header: &str) -> Option<ProfileName> {
+    match *header.split_whitespace().collect::<Vec<_>>() {
+        ["default"] => Some(ProfileName::Default),
+        [keyword, name] if keyword.eq_ignore_ascii_case("profile") => Some(name.into()),
+        _ => None,
+    }
+}
+
+/// Returns the keyword and name of a section header in ~/.aws/config other than a profile, such
+/// as `[sso-session my-sso]`, or `None` if the header is not one of them.
+pub fn config_section_name(header: &str) -> Option<(&'static str, &str)> {
+    match *header.split_whitespace().collect::<Vec<_>>() {
+        [keyword, name] => SECTION_KEYWORDS
+            .into_iter()
+            .find(|section_keyword| keyword.eq_ignore_ascii_case(section_keyword))
+            .map(|section_keyword| (section_keyword, name)),
+        _ => None,
+    }
+}
+
+/// Returns the profile name of a section header in ~/.aws/credentials.
+pub fn credentials_profile_name(header: &str) -> ProfileName {
+    header.trim().into()
+}
+
+/// Parses the contents of an INI file into entries. Errors carry the path and source snippet.
+pub fn parse_entries(contents: &str, path: &Path) -> Result<Vec<Entry>, ParseError> {
+    EntryLineLexer::new(contents)
+        .tokenize()
+        .and_then(|lines| EntryLineParser::new(lines).parse())
+        .map_err(|e| e.with_source(path, contents))
+}
+
+/// Resolves profiles defined more than once in a file according to `on_duplicate`. Sections are
+/// kept in the order they first appear. Also returns whether any duplicate was resolved.
+fn resolve_duplicates(
+    sections: Vec<(ProfileName, Entry)>,
+    on_duplicate: DuplicatePolicy,
+    path: &Path,
+    contents: &str,
+) -> Result<(Vec<(ProfileName, Entry)>, bool)> {
+    let mut resolved: Vec<(ProfileName, Entry)> = vec![];
+    let mut has_duplicates = false;
+    for (name, entry) in sections {
+        let Some((_, first)) = resolved.iter_mut().find(|(n, _)| *n == name) else {
+            resolved.push((name, entry));
+            continue;
+        };
+
+        match on_duplicate {
+            DuplicatePolicy::Error => {
+                let kind = ParseErrorKind::DuplicateSection {
+                    profile: name.to_string(),
+                    first_line: first.header_span.line,
+                };
+                return Err(ParseError::new(kind, Some(entry.header_span))
+                    .with_source(path, contents)
+                    .into());
+            }
+            DuplicatePolicy::Merge => merge_entry(first, entry),
+            DuplicatePolicy::KeepFirst => {}
+            DuplicatePolicy::KeepLast => *first = entry,
+        }
+        has_duplicates = true;
+    }
+
+    Ok((resolved, has_duplicates))
+}
+
+/// Options of the section without a field of their own, i.e. not in `keys`. Repeated keys are
+/// all kept.
+fn other_options(entry: &Entry, keys: &[&str]) -> Vec<RawOption> {
+    entry
+        .options
+        .iter()
+        .filter(|option| !keys.contains(&option.key.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Merges a later section of the same profile into `into`. Sections locked differently make the
+/// merged section inconsistent.
+fn merge_entry(into: &mut Entry, other: Entry) {
+    into.comments.extend(other.comments);
+    into.tier = into.tier.max(other.tier);
+    into.annotations.merge(other.annotations);
+    if other.is_locked != into.is_locked {
+        into.inconsistent_spans.push(other.header_span);
+    }
+    into.inconsistent_spans.extend(other.inconsistent_spans);
+    for key in other.values.keys() {
+        into.spelling.children.remove(key);
+    }
+    into.options
+        .retain(|option| !other.values.contains_key(&option.key));
+    into.options.extend(other.options);
+    into.values.extend(other.values);
+    into.value_spans.extend(other.value_spans);
+    into.spelling.merge(other.spelling);
+}
+
+/// Writes comment lines. Comments without a marker, as stored by older versions, get `# `.
+fn write_comments(out: &mut Vec<u8>, comments: &[String]) -> Result<()> {
+    for comment in comments {
+        if comment.starts_with('#') || comment.starts_with(';') {
+            writeln!(out, "{}", comment)?;
+        } else {
+            writeln!(out, "# {}", comment)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes comment lines inside a section. Comments that would be read as locked options, such as
+/// `#output = json`, are commented out once more in a locked section, and restored in an unlocked
+/// one.
+fn write_section_comments(
+    out: &mut Vec<u8>,
+    comments: &[String],
+    locked_prefix: &str,
+) -> Result<()> {
+    for comment in comments {
+        match strip_locked_comment(comment) {
+            Some(inner) if locked_prefix.is_empty() => writeln!(out, "{inner}")?,
+            None if is_locked_line(comment) => writeln!(out, "{locked_prefix}{comment}")?,
+            _ => writeln!(out, "{comment}")?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes an option if it has a value, along with the comments and spelling it was read with and
+/// the lines indented under it.
+fn write_option(
+    out: &mut Vec<u8>,
+    spelling: &Spelling,
+    locked_prefix: &str,
+    key: &str,
+    value: Option<&str>,
+) -> Result<()> {
+    if let Some(comments) = spelling.comments.get(key) {
+        write_section_comments(out, comments, locked_prefix)?;
+    }
+
+    if let Some(value) = value {
+        let spelled_key = spelling.keys.get(key).map_or(key, String::as_str);
+        let inline_comment = spelling.inline_comments.get(key).map_or("", String::as_str);
+        // Options with sub-options, such as `s3 =`, have no value of their own
+        let separator = if value.is_empty() { " =" } else { " = " };
+        writeln!(
+            out,
+            "{}{}{}{}{}",
+            locked_prefix, spelled_key, separator, value, inline_comment
+        )?;
+        for child in spelling.children.get(key).into_iter().flatten() {
+            writeln!(out, "{locked_prefix}{child}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes an option as it was read, along with the comments before it and the lines indented
+/// under it.
+fn write_raw_option(out: &mut Vec<u8>, locked_prefix: &str, option: &RawOption) -> Result<()> {
+    write_section_comments(out, &option.comments, locked_prefix)?;
+    writeln!(out, "{locked_prefix}{}", option.text)?;
+    for child in &option.children {
+        writeln!(out, "{locked_prefix}{child}")?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the contents of the file, converting line endings to CRLF if `crlf` is set.
+fn overwrite(file: &mut File, contents: &[u8], crlf: bool) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    if crlf {
+        let contents = String::from_utf8_lossy(contents).replace('\n', "\r\n");
+        file.write_all(contents.as_bytes())?;
+    } else {
+        file.write_all(contents)?;
+    }
+
+    Ok(())
+}
+
+fn read_entries(file: &mut File, path: &Path) -> Result<(String, Vec<Entry>)> {
+    let mut buf = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut buf)?;
+    let entries = parse_entries(&buf, path)?;
+
+    Ok((buf, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::{env, fs};
+
+    const DUPLICATED: &str = "[profile a]\n\
+                              region = us-east-1\n\
+                              output = json\n\
+                              \n\
+                              [profile b]\n\
+                              region = eu-west-1\n\
+                              \n\
+                              [profile a]\n\
+                              region = ap-northeast-1\n";
+
+    fn resolve(
+        contents: &str,
+        on_duplicate: DuplicatePolicy,
+    ) -> Result<(Vec<(ProfileName, Entry)>, bool)> {
+        let path = Path::new("config");
+        let sections = parse_entries(contents, path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (config_profile_name(&entry.header).unwrap(), entry))
+            .collect();
+        resolve_duplicates(sections, on_duplicate, path, contents)
+    }
+
+    fn names(sections: &[(ProfileName, Entry)]) -> Vec<String> {
+        sections.iter().map(|(name, _)| name.to_string()).collect()
+    }
+
+    /// Writes the files, applies `update` to the profiles and returns the files as written.
+    fn rewrite(
+        name: &str,
+        config: &str,
+        credentials: &str,
+        update: impl Fn(&mut AwsProfile),
+    ) -> (String, String) {
+        let dir = env::temp_dir().join(format!("aws-unlock-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (config_path, credentials_path) = (dir.join("config"), dir.join("credentials"));
+        fs::write(&config_path, config).unwrap();
+        fs::write(&credentials_path, credentials).unwrap();
+
+        let mut aws_file = AwsFile::open_at(
+            config_path.clone(),
+            credentials_path.clone(),
+            Settings::default(),
+        )
+        .unwrap();
+        let mut profiles = aws_file.parse().unwrap();
+        profiles.iter_mut().for_each(update);
+        aws_file.write(&profiles).unwrap();
+        aws_file.flush().unwrap();
+
+        let written = (
+            fs::read_to_string(&config_path).unwrap(),
+            fs::read_to_string(&credentials_path).unwrap(),
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        written
+    }
+
+    #[test]
+    fn nested_options_survive_lock_and_unlock() {
+        let config = "\
+[profile dev]
+region = us-east-1
+output = json
+s3 =
+  max_concurrent_requests = 20
+  multipart_threshold = 64MB
+
+[services my-svc]
+s3 =
+  endpoint_url = http://localhost:4566
+dynamodb =
+  endpoint_url = http://localhost:8000
+";
+        let credentials = "\
+[dev]
+aws_access_key_id = AKIAEXAMPLE
+aws_secret_access_key = secret
+";
+
+        let lock = |profile: &mut AwsProfile| profile.lock_state = LockState::Locked;
+        let (locked_config, locked_credentials) = rewrite("nested", config, credentials, lock);
+        assert!(locked_config.contains("# s3 =\n#   max_concurrent_requests = 20\n"));
+
+        let unlock = |profile: &mut AwsProfile| profile.lock_state = LockState::Unlocked;
+        let (config_again, credentials_again) =
+            rewrite("nested", &locked_config, &locked_credentials, unlock);
+        assert_eq!(config_again, config);
+        assert_eq!(credentials_again, credentials);
+    }
+
+    #[test]
+    fn repeated_and_nested_options_are_written_as_read() {
+        let config = "\
+[profile dev]
+region = us-east-1
+# first endpoint
+Endpoint_URL = http://localhost:4566  # localstack
+endpoint_url = http://localhost:8000
+s3 =
+  endpoint_url = http://localhost:9000
+  max_concurrent_requests = 20
+
+[services my-svc]
+s3 =
+  endpoint_url = http://localhost:4566
+s3 =
+  endpoint_url = http://localhost:9000
+";
+        let credentials = "\
+[dev]
+aws_access_key_id = AKIAEXAMPLE
+aws_secret_access_key = secret
+";
+
+        let (written, _) = rewrite("raw", config, credentials, |_| {});
+        assert_eq!(written, config);
+
+        let lock = |profile: &mut AwsProfile| profile.lock_state = LockState::Locked;
+        let (locked, _) = rewrite("raw", config, credentials, lock);
+        let unlock = |profile: &mut AwsProfile| profile.lock_state = LockState::Unlocked;
+        let (unlocked, _) = rewrite("raw", &locked, credentials, unlock);
+        assert_eq!(unlocked, config);
+    }
+
+    #[test]
+    fn half_locked_credentials_are_inconsistent_and_repairable() {
+        let config = "[profile dev]\nregion = us-east-1\n";
+        let credentials = "\
+[dev]
+# aws_access_key_id = AKIAEXAMPLE
+# aws_secret_access_key = secret
+# mfa_serial = arn:aws:iam::123456789012:mfa/dev
+";
+
+        let repair = |profile: &mut AwsProfile| {
+            let cred = profile.data.cred.as_ref().unwrap();
+            assert_eq!(cred.aws_access_key_id, "AKIAEXAMPLE");
+            assert_eq!(cred.other_options.len(), 1);
+            assert_eq!(profile.lock_state, LockState::Inconsistent);
+            profile.lock_state = LockState::Locked;
+        };
+        let (_, repaired) = rewrite("half-locked", config, credentials, repair);
+        assert_eq!(
+            repaired,
+            format!("# [dev]\n{}", &credentials["[dev]\n".len()..])
+        );
+    }
+
+    #[test]
+    fn duplicates_are_errors_by_default() {
+        let err = resolve(DUPLICATED, DuplicatePolicy::default()).unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+        assert!(err.to_string().contains('a'), "{err}");
+    }
+
+    #[test]
+    fn merge_prefers_later_options() {
+        let (sections, has_duplicates) = resolve(DUPLICATED, DuplicatePolicy::Merge).unwrap();
+        assert!(has_duplicates);
+        assert_eq!(names(&sections), ["a", "b"]);
+        let entry = &sections[0].1;
+        assert_eq!(entry.values["region"], "ap-northeast-1");
+        assert_eq!(entry.values["output"], "json");
+        let keys: Vec<_> = entry.options.iter().map(|option| &option.key).collect();
+        assert_eq!(keys, ["output", "region"]);
+    }
+
+    #[test]
+    fn keep_first_and_keep_last_drop_the_others() {
+        let (sections, _) = resolve(DUPLICATED, DuplicatePolicy::KeepFirst).unwrap();
+        assert_eq!(names(&sections), ["a", "b"]);
+        assert_eq!(sections[0].1.values["region"], "us-east-1");
+
+        let (sections, _) = resolve(DUPLICATED, DuplicatePolicy::KeepLast).unwrap();
+        assert_eq!(names(&sections), ["a", "b"]);
+        assert_eq!(sections[0].1.values["region"], "ap-northeast-1");
+        assert!(!sections[0].1.values.contains_key("output"));
+    }
+
+    #[test]
+    fn merging_differently_locked_sections_is_inconsistent() {
+        let contents = "[profile a]\n\
+                        region = us-east-1\n\
+                        \n\
+                        # [profile a]\n\
+                        # output = json\n";
+        let (sections, _) = resolve(contents, DuplicatePolicy::Merge).unwrap();
+        assert_eq!(sections[0].1.lock_state(), LockState::Inconsistent);
+    }
+
+    #[test]
+    fn unique_sections_are_kept_as_is() {
+        let contents = "[profile a]\nregion = us-east-1\n";
+        for on_duplicate in [DuplicatePolicy::Error, DuplicatePolicy::Merge] {
+            let (sections, has_duplicates) = resolve(contents, on_duplicate).unwrap();
+            assert!(!has_duplicates);
+            assert_eq!(names(&sections), ["a"]);
+        }
     }
 }
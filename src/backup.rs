@@ -0,0 +1,59 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    app_dir::{app_dir, create_private_dir, write_private_file},
+    error::Result,
+};
+
+/// Copies `path` into `~/.aws-unlock/backups` before aws-unlock overwrites it in a way that
+/// drops data, keeping the latest `retention` backups of the file (all if 0). Returns the path
+/// of the copy.
+pub fn backup_file(path: &Path, retention: usize) -> Result<PathBuf> {
+    let backups_dir = app_dir()?.join("backups");
+    create_private_dir(&backups_dir)?;
+
+    let file_name = path
+        .file_name()
+        .expect("backed up file has a name")
+        .to_string_lossy();
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_millis())
+        .unwrap_or(0);
+    let backup_path = backups_dir.join(format!("{file_name}.{millis}"));
+    write_private_file(&backup_path, &fs::read(path)?)?;
+    prune_backups(&backups_dir, &file_name, retention)?;
+
+    Ok(backup_path)
+}
+
+/// Removes all but the latest `retention` backups of `file_name`.
+fn prune_backups(backups_dir: &Path, file_name: &str, retention: usize) -> Result<()> {
+    if retention == 0 {
+        return Ok(());
+    }
+
+    let prefix = format!("{file_name}.");
+    let mut backups = vec![];
+    for entry in fs::read_dir(backups_dir)? {
+        let path = entry?.path();
+        let millis = path
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_prefix(&prefix)?.parse::<u128>().ok());
+        if let Some(millis) = millis {
+            backups.push((millis, path));
+        }
+    }
+
+    backups.sort();
+    let excess = backups.len().saturating_sub(retention);
+    for (_, path) in backups.into_iter().take(excess) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
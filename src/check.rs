@@ -0,0 +1,573 @@
+use home::home_dir;
+use humantime::parse_rfc3339_weak;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    aws_profile::{
+        aws_file_paths, config_profile_name, config_section_name, credentials_profile_name,
+        parse_entries, ProfileName, SSO_SESSION_KEYWORD,
+    },
+    error::Result,
+    line_lexer::Span,
+    line_parser::Entry,
+    parse_error::ParseErrorKind,
+};
+
+/// Keys the AWS SDKs resolve credentials from in ~/.aws/config.
+const CREDENTIAL_SOURCE_KEYS: [&str; 6] = [
+    "role_arn",
+    "sso_session",
+    "sso_start_url",
+    "credential_process",
+    "web_identity_token_file",
+    "credential_source",
+];
+
+/// A problem found by [`check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub path: PathBuf,
+
+    /// Location of the problem. `None` if it concerns the whole file.
+    pub span: Option<Span>,
+
+    pub kind: ProblemKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ProblemKind {
+    #[error("file not found")]
+    NotFound,
+
+    /// The file cannot be parsed. Nothing after this problem is checked in the file.
+    #[error("{0}")]
+    Parse(ParseErrorKind),
+
+    #[error("profile '{profile}' is in ~/.aws/credentials but not in ~/.aws/config")]
+    OnlyInCredentials { profile: ProfileName },
+
+    #[error(
+        "profile '{profile}' has neither credentials in ~/.aws/credentials nor a credential source"
+    )]
+    OnlyInConfig { profile: ProfileName },
+
+    #[error(
+        "half-locked section [{header}]: {}",
+        if *is_header_locked {
+            "the header is commented out but this option is not"
+        } else {
+            "this option is commented out but the header is not"
+        }
+    )]
+    HalfLocked {
+        header: String,
+        is_header_locked: bool,
+    },
+
+    #[error("profile '{profile}' refers to [sso-session {session}], which is not defined")]
+    UnknownSsoSession {
+        profile: ProfileName,
+        session: String,
+    },
+
+    #[error("profile '{profile}' has a temporary (ASIA) access key but no aws_session_token")]
+    MissingSessionToken { profile: ProfileName },
+
+    #[error("session of profile '{profile}' expired at {expiration}")]
+    SessionExpired {
+        profile: ProfileName,
+        expiration: String,
+    },
+
+    #[error("invalid aws_session_expiration of profile '{profile}': {expiration:?}")]
+    InvalidSessionExpiration {
+        profile: ProfileName,
+        expiration: String,
+    },
+
+    #[error("permissions {mode:03o} let other users {access} this file")]
+    InsecurePermissions { mode: u32, access: &'static str },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        write!(b, "{}", self.path.display())?;
+        if let Some(span) = self.span {
+            write!(b, ":{}:{}", span.line, span.column)?;
+        }
+
+        write!(b, ": {}", self.kind)
+    }
+}
+
+/// Validates ~/.aws/config and ~/.aws/credentials without modifying them, along with the
+/// permissions of aws-unlock's own secret files.
+pub fn check() -> Result<Vec<Problem>> {
+    let (config_path, credentials_path) = aws_file_paths();
+    let home_dir = home_dir().expect("failed to locate home directory");
+    check_at(
+        &config_path,
+        &credentials_path,
+        &home_dir.join(".aws-unlock"),
+    )
+}
+
+fn check_at(config_path: &Path, credentials_path: &Path, app_dir: &Path) -> Result<Vec<Problem>> {
+    let mut problems = vec![];
+
+    let config = read_file(config_path, &mut problems)?;
+    let credentials = read_file(credentials_path, &mut problems)?;
+
+    let config = config.and_then(|contents| {
+        let entries = parse_file(config_path, &contents, &mut problems)?;
+        let mut sso_sessions = vec![];
+        let entries = entries
+            .into_iter()
+            .filter_map(|entry| {
+                if let Some(name) = config_profile_name(&entry.header) {
+                    return Some((name, entry));
+                }
+
+                match config_section_name(&entry.header) {
+                    Some((keyword, name)) if keyword == SSO_SESSION_KEYWORD => {
+                        sso_sessions.push(name.to_string())
+                    }
+                    Some(_) => {}
+                    None => {
+                        let kind = ParseErrorKind::UnknownHeader {
+                            header: entry.header.clone(),
+                        };
+                        let span = Some(entry.header_span);
+                        problems.push(problem(config_path, span, ProblemKind::Parse(kind)));
+                    }
+                }
+                None
+            })
+            .collect();
+
+        let entries = check_sections(config_path, entries, &mut problems);
+        for (name, entry) in &entries {
+            let Some(session) = entry.values.get("sso_session") else {
+                continue;
+            };
+            if !sso_sessions.contains(session) {
+                let span = entry.value_spans.get("sso_session").copied();
+                let kind = ProblemKind::UnknownSsoSession {
+                    profile: name.clone(),
+                    session: session.clone(),
+                };
+                problems.push(problem(config_path, span, kind));
+            }
+        }
+
+        Some(entries)
+    });
+
+    let credentials = credentials.and_then(|contents| {
+        let entries = parse_file(credentials_path, &contents, &mut problems)?;
+        let entries = entries
+            .into_iter()
+            .map(|entry| (credentials_profile_name(&entry.header), entry))
+            .collect();
+        let entries = check_sections(credentials_path, entries, &mut problems);
+        for (name, entry) in &entries {
+            check_credentials(credentials_path, name, entry, &mut problems);
+        }
+
+        Some(entries)
+    });
+
+    // Only compare the files if both could be parsed, otherwise every profile would be reported
+    if let (Some(config), Some(credentials)) = (config, credentials) {
+        for (name, entry) in &credentials {
+            if config.iter().all(|(n, _)| n != name) {
+                let kind = ProblemKind::OnlyInCredentials {
+                    profile: name.clone(),
+                };
+                problems.push(problem(credentials_path, Some(entry.header_span), kind));
+            }
+        }
+
+        for (name, entry) in &config {
+            let has_source = CREDENTIAL_SOURCE_KEYS
+                .iter()
+                .any(|key| entry.values.contains_key(*key));
+            if !has_source && credentials.iter().all(|(n, _)| n != name) {
+                let kind = ProblemKind::OnlyInConfig {
+                    profile: name.clone(),
+                };
+                problems.push(problem(config_path, Some(entry.header_span), kind));
+            }
+        }
+    }
+
+    check_permissions(config_path, 0o022, &mut problems)?;
+    check_permissions(credentials_path, 0o077, &mut problems)?;
+    for file_name in ["credentials.json", "vault.json"] {
+        check_permissions(&app_dir.join(file_name), 0o077, &mut problems)?;
+    }
+
+    Ok(problems)
+}
+
+fn problem(path: &Path, span: Option<Span>, kind: ProblemKind) -> Problem {
+    Problem {
+        path: path.to_path_buf(),
+        span,
+        kind,
+    }
+}
+
+fn read_file(path: &Path, problems: &mut Vec<Problem>) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            problems.push(problem(path, None, ProblemKind::NotFound));
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_file(path: &Path, contents: &str, problems: &mut Vec<Problem>) -> Option<Vec<Entry>> {
+    match parse_entries(contents, path) {
+        Ok(entries) => Some(entries),
+        Err(e) => {
+            problems.push(problem(path, e.span, ProblemKind::Parse(e.kind)));
+            None
+        }
+    }
+}
+
+/// Reports duplicate and half-locked sections and invalid annotations. Duplicates are dropped from the returned
+/// sections.
+fn check_sections(
+    path: &Path,
+    entries: Vec<(ProfileName, Entry)>,
+    problems: &mut Vec<Problem>,
+) -> Vec<(ProfileName, Entry)> {
+    let mut first_lines = HashMap::new();
+    let mut sections = vec![];
+    for (name, entry) in entries {
+        for &span in &entry.inconsistent_spans {
+            let kind = ProblemKind::HalfLocked {
+                header: entry.header.clone(),
+                is_header_locked: entry.is_locked,
+            };
+            problems.push(problem(path, Some(span), kind));
+        }
+
+        for (key, reason) in entry.annotations.invalid() {
+            let kind = ParseErrorKind::InvalidAnnotation {
+                key: key.to_string(),
+                reason,
+            };
+            let span = Some(entry.header_span);
+            problems.push(problem(path, span, ProblemKind::Parse(kind)));
+        }
+
+        if let Some(&first_line) = first_lines.get(&name) {
+            let kind = ParseErrorKind::DuplicateSection {
+                profile: name.to_string(),
+                first_line,
+            };
+            let span = Some(entry.header_span);
+            problems.push(problem(path, span, ProblemKind::Parse(kind)));
+            continue;
+        }
+
+        first_lines.insert(name.clone(), entry.header_span.line);
+        sections.push((name, entry));
+    }
+
+    sections
+}
+
+fn check_credentials(path: &Path, name: &ProfileName, entry: &Entry, problems: &mut Vec<Problem>) {
+    for key in ["aws_access_key_id", "aws_secret_access_key"] {
+        if !entry.values.contains_key(key) {
+            let kind = ParseErrorKind::MissingKey {
+                profile: name.to_string(),
+                key: key.to_string(),
+            };
+            problems.push(problem(
+                path,
+                Some(entry.header_span),
+                ProblemKind::Parse(kind),
+            ));
+        }
+    }
+
+    let is_temporary = entry
+        .values
+        .get("aws_access_key_id")
+        .is_some_and(|key| key.starts_with("ASIA"));
+    let has_token = ["aws_session_token", "aws_security_token"]
+        .iter()
+        .any(|key| entry.values.contains_key(*key));
+    if is_temporary && !has_token {
+        let span = entry.value_spans.get("aws_access_key_id").copied();
+        let kind = ProblemKind::MissingSessionToken {
+            profile: name.clone(),
+        };
+        problems.push(problem(path, span, kind));
+    }
+
+    if let Some(expiration) = entry.values.get("aws_session_expiration") {
+        let span = entry.value_spans.get("aws_session_expiration").copied();
+        let kind = match parse_expiration(expiration) {
+            Some(expires_at) if expires_at > SystemTime::now() => return,
+            Some(_) => ProblemKind::SessionExpired {
+                profile: name.clone(),
+                expiration: expiration.clone(),
+            },
+            None => ProblemKind::InvalidSessionExpiration {
+                profile: name.clone(),
+                expiration: expiration.clone(),
+            },
+        };
+        problems.push(problem(path, span, kind));
+    }
+}
+
+/// Parses an RFC 3339 timestamp in UTC, written either with `Z` or `+00:00`.
+fn parse_expiration(expiration: &str) -> Option<SystemTime> {
+    let expiration = expiration.trim();
+    let expiration = expiration
+        .strip_suffix("+00:00")
+        .map_or_else(|| expiration.to_string(), |time| format!("{time}Z"));
+
+    parse_rfc3339_weak(&expiration).ok()
+}
+
+/// Reports the file if any of the `denied` permission bits are set. Missing files are skipped.
+#[cfg(unix)]
+fn check_permissions(path: &Path, denied: u32, problems: &mut Vec<Problem>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & denied != 0 {
+        let access = if mode & denied & 0o044 != 0 {
+            "read"
+        } else {
+            "write"
+        };
+        problems.push(problem(
+            path,
+            None,
+            ProblemKind::InsecurePermissions { mode, access },
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path, _denied: u32, _problems: &mut Vec<Problem>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::PermissionsExt};
+
+    /// Checks files holding `config` and `credentials`, the latter with permissions `mode`,
+    /// returning the problems by file name and line.
+    fn check_files(
+        name: &str,
+        config: &str,
+        credentials: &str,
+        mode: u32,
+    ) -> Vec<(String, usize, ProblemKind)> {
+        let dir = env::temp_dir().join(format!("aws-unlock-check-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (config_path, credentials_path) = (dir.join("config"), dir.join("credentials"));
+        fs::write(&config_path, config).unwrap();
+        fs::write(&credentials_path, credentials).unwrap();
+        fs::set_permissions(&credentials_path, fs::Permissions::from_mode(mode)).unwrap();
+
+        let problems = check_at(&config_path, &credentials_path, &dir).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        problems
+            .into_iter()
+            .map(|problem| {
+                let file_name = problem
+                    .path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                let line = problem.span.map_or(0, |span| span.line);
+                (file_name, line, problem.kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn consistent_files_have_no_problems() {
+        let config = "\
+[profile dev]
+region = us-east-1
+
+[profile sso]
+sso_session = corp
+
+[sso-session corp]
+sso_start_url = https://corp.awsapps.com/start
+";
+        let credentials = "\
+[dev]
+aws_access_key_id = AKIAEXAMPLE
+aws_secret_access_key = secret
+";
+        assert_eq!(check_files("consistent", config, credentials, 0o600), []);
+    }
+
+    #[test]
+    fn problems_are_reported_with_their_lines() {
+        let config = "\
+[profile dev]
+[profile orphan]
+[profile sso]
+sso_session = missing
+[dev]
+";
+        let credentials = "\
+[dev]
+aws_access_key_id = ASIAEXAMPLE
+aws_secret_access_key = secret
+aws_session_expiration = 2020-01-01T00:00:00Z
+[dev]
+[stray]
+aws_access_key_id = AKIAEXAMPLE
+aws_session_expiration = tomorrow
+";
+        let problems = check_files("problems", config, credentials, 0o600);
+        let dev = ProfileName::from("dev");
+        let stray = ProfileName::from("stray");
+        let expected = [
+            (
+                "config",
+                5,
+                ProblemKind::Parse(ParseErrorKind::UnknownHeader {
+                    header: "dev".to_string(),
+                }),
+            ),
+            (
+                "config",
+                4,
+                ProblemKind::UnknownSsoSession {
+                    profile: "sso".into(),
+                    session: "missing".to_string(),
+                },
+            ),
+            (
+                "credentials",
+                5,
+                ProblemKind::Parse(ParseErrorKind::DuplicateSection {
+                    profile: "dev".to_string(),
+                    first_line: 1,
+                }),
+            ),
+            (
+                "credentials",
+                2,
+                ProblemKind::MissingSessionToken {
+                    profile: dev.clone(),
+                },
+            ),
+            (
+                "credentials",
+                4,
+                ProblemKind::SessionExpired {
+                    profile: dev,
+                    expiration: "2020-01-01T00:00:00Z".to_string(),
+                },
+            ),
+            (
+                "credentials",
+                6,
+                ProblemKind::Parse(ParseErrorKind::MissingKey {
+                    profile: "stray".to_string(),
+                    key: "aws_secret_access_key".to_string(),
+                }),
+            ),
+            (
+                "credentials",
+                8,
+                ProblemKind::InvalidSessionExpiration {
+                    profile: stray.clone(),
+                    expiration: "tomorrow".to_string(),
+                },
+            ),
+            (
+                "credentials",
+                6,
+                ProblemKind::OnlyInCredentials { profile: stray },
+            ),
+            (
+                "config",
+                2,
+                ProblemKind::OnlyInConfig {
+                    profile: "orphan".into(),
+                },
+            ),
+        ]
+        .map(|(file_name, line, kind)| (file_name.to_string(), line, kind));
+        assert_eq!(problems, expected);
+    }
+
+    #[test]
+    fn half_locked_sections_are_reported() {
+        let config = "[profile dev]\n# region = us-east-1\n";
+        let credentials = "\
+# [dev]
+# aws_access_key_id = AKIAEXAMPLE
+aws_secret_access_key = secret
+";
+        let problems = check_files("half-locked", config, credentials, 0o600);
+        assert_eq!(
+            problems,
+            [(
+                "credentials".to_string(),
+                3,
+                ProblemKind::HalfLocked {
+                    header: "dev".to_string(),
+                    is_header_locked: true,
+                },
+            )]
+        );
+    }
+
+    #[test]
+    fn readable_credentials_are_reported() {
+        let credentials =
+            "[dev]\naws_access_key_id = AKIAEXAMPLE\naws_secret_access_key = secret\n";
+        let problems = check_files("mode", "[profile dev]\n", credentials, 0o644);
+        assert_eq!(
+            problems,
+            [(
+                "credentials".to_string(),
+                0,
+                ProblemKind::InsecurePermissions {
+                    mode: 0o644,
+                    access: "read",
+                },
+            )]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::VecDeque,
+    io::{stdin, stdout, IsTerminal, Write},
+    thread,
+    time::Duration,
+};
+
+use crate::{aws_profile::ProfileName, error::Result};
+
+/// Answers the questions asked before unlocking profiles that require confirmation, such as
+/// `Are you sure? (y/N)` or the name of the profile to type in. Lets library users put the
+/// prompts in a GUI or answer them in tests instead of the terminal.
+pub trait Confirmer {
+    /// Shows `message` and returns the answer without surrounding whitespace. An empty answer
+    /// declines.
+    fn ask(&mut self, message: &str) -> Result<String>;
+
+    /// Shows `message` and waits for `duration` before the next question, so that it is read
+    /// before being answered.
+    fn wait(&mut self, message: &str, duration: Duration) -> Result<()> {
+        let _ = message;
+        thread::sleep(duration);
+        Ok(())
+    }
+
+    /// Whether anyone can answer. If not, unlocking profiles that require confirmation fails
+    /// without asking unless they are approved.
+    fn is_interactive(&self) -> bool {
+        true
+    }
+
+    /// Whether unlocking the profile is approved in advance, so that it needs no confirmation.
+    fn is_approved(&self, profile: &ProfileName) -> bool {
+        let _ = profile;
+        false
+    }
+
+    /// Whether commands that command rules require to confirm are approved in advance for the
+    /// profile. Approving the profile with [`Confirmer::is_approved`] does not approve them.
+    fn is_command_approved(&self, profile: &ProfileName) -> bool {
+        let _ = profile;
+        false
+    }
+}
+
+/// Asks on the terminal. Only interactive when stdin is a terminal.
+#[derive(Debug, Clone, Default)]
+pub struct TerminalConfirmer {
+    /// Profiles approved in advance, e.g. by `--yes-production`.
+    pub approved_profiles: Vec<ProfileName>,
+
+    /// Profiles whose commands are approved in advance, e.g. by `--yes-command`.
+    pub approved_command_profiles: Vec<ProfileName>,
+}
+
+impl TerminalConfirmer {
+    pub fn new(
+        approved_profiles: Vec<ProfileName>,
+        approved_command_profiles: Vec<ProfileName>,
+    ) -> Self {
+        Self {
+            approved_profiles,
+            approved_command_profiles,
+        }
+    }
+}
+
+impl Confirmer for TerminalConfirmer {
+    fn ask(&mut self, message: &str) -> Result<String> {
+        print!("{message}");
+        stdout().flush()?;
+        let mut buf = String::new();
+        stdin().read_line(&mut buf)?;
+
+        Ok(buf.trim().to_string())
+    }
+
+    fn wait(&mut self, message: &str, duration: Duration) -> Result<()> {
+        print!("{message}");
+        stdout().flush()?;
+        thread::sleep(duration);
+        discard_typeahead();
+        println!();
+
+        Ok(())
+    }
+
+    fn is_interactive(&self) -> bool {
+        stdin().is_terminal()
+    }
+
+    fn is_approved(&self, profile: &ProfileName) -> bool {
+        self.approved_profiles.contains(profile)
+    }
+
+    fn is_command_approved(&self, profile: &ProfileName) -> bool {
+        self.approved_command_profiles.contains(profile)
+    }
+}
+
+/// Declines everything without waiting.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DenyConfirmer;
+
+impl Confirmer for DenyConfirmer {
+    fn ask(&mut self, _message: &str) -> Result<String> {
+        Ok(String::new())
+    }
+
+    fn wait(&mut self, _message: &str, _duration: Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Gives prepared answers in order, and declines once they run out. Records the questions, and
+/// does not wait.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedConfirmer {
+    answers: VecDeque<String>,
+
+    /// Messages shown so far, in order.
+    pub messages: Vec<String>,
+}
+
+impl ScriptedConfirmer {
+    pub fn new<I, S>(answers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            answers: answers.into_iter().map(Into::into).collect(),
+            messages: vec![],
+        }
+    }
+}
+
+impl Confirmer for ScriptedConfirmer {
+    fn ask(&mut self, message: &str) -> Result<String> {
+        self.messages.push(message.to_string());
+        Ok(self.answers.pop_front().unwrap_or_default())
+    }
+
+    fn wait(&mut self, message: &str, _duration: Duration) -> Result<()> {
+        self.messages.push(message.to_string());
+        Ok(())
+    }
+}
+
+/// Passes each question to a closure, e.g. one showing a dialog.
+pub struct CallbackConfirmer<F> {
+    callback: F,
+}
+
+impl<F> CallbackConfirmer<F>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> Confirmer for CallbackConfirmer<F>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    fn ask(&mut self, message: &str) -> Result<String> {
+        Ok((self.callback)(message)?.trim().to_string())
+    }
+}
+
+/// Discards input typed ahead, so that keystrokes made before a prompt cannot answer it.
+#[cfg(unix)]
+fn discard_typeahead() {
+    // SAFETY: tcflush only takes a file descriptor and a constant, and touches no memory of
+    // ours. On a descriptor that is not a terminal it fails with ENOTTY, which is ignored.
+    unsafe { libc::tcflush(libc::STDIN_FILENO, libc::TCIFLUSH) };
+}
+
+#[cfg(not(unix))]
+fn discard_typeahead() {}
@@ -0,0 +1,104 @@
+use humantime::format_rfc3339_seconds;
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    aws_profile::{AwsFile, ProfileName},
+    credential_store::{find_credentials, CredentialStore},
+    error::{Error, Result},
+    lease::Lease,
+    line_parser::LockState,
+    secret::Secret,
+    vault::{is_sealed, Vault},
+};
+
+/// Lifetime of credentials handed out under a lease without a deadline. Kept short so the SDK
+/// asks again soon and stops getting credentials shortly after the holder exits.
+pub const OPEN_LEASE_EXPIRATION: Duration = Duration::from_secs(60);
+
+/// Output of a `credential_process`, as documented in "Sourcing credentials with an external
+/// process" of the AWS CLI user guide.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CredentialProcessOutput<'a> {
+    version: u32,
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_token: Option<&'a str>,
+    expiration: String,
+}
+
+/// Returns the `credential_process` JSON for the profile, or an error if the profile has no
+/// active unlock lease.
+pub fn credential_process_output(name: &ProfileName) -> Result<String> {
+    let Some(lease) = Lease::active(name)? else {
+        return Err(Error::Locked(name.clone()));
+    };
+
+    let cred = find_credentials(name)?;
+
+    let expiration = lease
+        .expires_at()
+        .unwrap_or_else(|| SystemTime::now() + OPEN_LEASE_EXPIRATION);
+    let output = CredentialProcessOutput {
+        version: 1,
+        access_key_id: &cred.aws_access_key_id,
+        secret_access_key: cred.aws_secret_access_key.expose(),
+        session_token: cred.aws_session_token.as_ref().map(Secret::expose),
+        expiration: format_rfc3339_seconds(expiration).to_string(),
+    };
+
+    Ok(serde_json::to_string_pretty(&output)?)
+}
+
+/// Rewrites the profile to source its credentials from `aws-unlock credential-process` and
+/// moves its static keys from ~/.aws/credentials into the credential store. Keys locked into the
+/// vault are decrypted first. The profile is left locked.
+pub fn install(name: &ProfileName) -> Result<()> {
+    let mut aws_file = AwsFile::open()?;
+    let mut profiles = aws_file.parse()?;
+    let Some(profile) = profiles.iter_mut().find(|profile| profile.name == *name) else {
+        return Err(Error::UnknownProfiles(vec![name.clone()]));
+    };
+    let Some(mut cred) = profile.data.cred.take() else {
+        return Err(Error::NoCredentials(name.clone()));
+    };
+    if is_sealed(&cred) {
+        Vault::open()?.unseal(name, &mut cred)?;
+    }
+
+    let mut store = CredentialStore::open()?;
+    store.insert(name, cred);
+    store.save()?;
+
+    profile.data.conf.credential_process = Some(credential_process_command(name));
+    profile.lock_state = LockState::Locked;
+    aws_file.write(&profiles)?;
+    aws_file.flush()?;
+
+    Ok(())
+}
+
+/// Returns the `credential_process` line for the profile. The name is quoted, since the AWS CLI
+/// splits the line like a shell does.
+fn credential_process_command(name: &ProfileName) -> String {
+    let quoted = name.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+    format!("aws-unlock credential-process \"{quoted}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credential_process_quotes_the_profile_name() {
+        let command = |name: &str| credential_process_command(&ProfileName::from(name));
+        assert_eq!(command("dev"), r#"aws-unlock credential-process "dev""#);
+        assert_eq!(
+            command(r#"my "team" dev; rm -rf ~"#),
+            r#"aws-unlock credential-process "my \"team\" dev; rm -rf ~""#
+        );
+        assert_eq!(command(r"a\b"), r#"aws-unlock credential-process "a\\b""#);
+    }
+}
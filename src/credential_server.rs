@@ -0,0 +1,246 @@
+use humantime::format_rfc3339_seconds;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+};
+
+use crate::{
+    aws_profile::AwsCredentialData, credential_process::OPEN_LEASE_EXPIRATION, error::Result,
+    secret::Secret,
+};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Response body of the container credentials endpoint, the format the SDKs expect from
+/// `AWS_CONTAINER_CREDENTIALS_FULL_URI`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentials<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<&'a str>,
+    expiration: String,
+}
+
+/// A loopback HTTP server handing out credentials in the ECS container credentials format to
+/// clients presenting its random authorization token.
+#[derive(Debug)]
+pub struct CredentialServer {
+    listener: TcpListener,
+    state: Arc<ServerState>,
+}
+
+#[derive(Debug)]
+struct ServerState {
+    token: String,
+    cred: AwsCredentialData,
+    expires_at: Option<SystemTime>,
+}
+
+impl CredentialServer {
+    /// Binds to a random port on 127.0.0.1. After `expires_at`, every request is answered with
+    /// 403.
+    pub async fn bind(
+        cred: AwsCredentialData,
+        expires_at: Option<SystemTime>,
+    ) -> Result<CredentialServer> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let token = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        Ok(CredentialServer {
+            listener,
+            state: Arc::new(ServerState {
+                token,
+                cred,
+                expires_at,
+            }),
+        })
+    }
+
+    /// Value for `AWS_CONTAINER_CREDENTIALS_FULL_URI`.
+    pub fn uri(&self) -> Result<String> {
+        let addr: SocketAddr = self.listener.local_addr()?;
+        Ok(format!("http://{addr}/credentials"))
+    }
+
+    /// Value for `AWS_CONTAINER_AUTHORIZATION_TOKEN`.
+    pub fn token(&self) -> &str {
+        &self.state.token
+    }
+
+    /// Serves requests until the returned future is dropped.
+    pub async fn serve(self) -> Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let state = Arc::clone(&self.state);
+            spawn(async move {
+                let _ = handle(stream, &state).await;
+            });
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, state: &ServerState) -> Result<()> {
+    let mut buf = vec![];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let mut chunk = [0; 1024];
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 || buf.len() + len > MAX_REQUEST_SIZE {
+            return respond(&mut stream, "400 Bad Request", "bad request").await;
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut lines = request.lines();
+    let is_get = lines
+        .next()
+        .is_some_and(|request_line| request_line.starts_with("GET "));
+    let authorization = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .map(|(_, value)| value.trim());
+
+    if !is_get {
+        return respond(&mut stream, "405 Method Not Allowed", "method not allowed").await;
+    }
+
+    if authorization != Some(state.token.as_str()) {
+        return respond(&mut stream, "403 Forbidden", "invalid authorization token").await;
+    }
+
+    let now = SystemTime::now();
+    if state.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return respond(&mut stream, "403 Forbidden", "profile is locked").await;
+    }
+
+    let body = serde_json::to_string(&ContainerCredentials {
+        access_key_id: &state.cred.aws_access_key_id,
+        secret_access_key: state.cred.aws_secret_access_key.expose(),
+        token: state.cred.aws_session_token.as_ref().map(Secret::expose),
+        expiration: format_rfc3339_seconds(state.expires_at.unwrap_or(now + OPEN_LEASE_EXPIRATION))
+            .to_string(),
+    })?;
+
+    respond(&mut stream, "200 OK", &body).await
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let content_type = if status.starts_with("200") {
+        "application/json"
+    } else {
+        "text/plain"
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cred() -> AwsCredentialData {
+        AwsCredentialData {
+            aws_access_key_id: "ASIAEXAMPLE".to_string(),
+            aws_secret_access_key: Secret::new("secret"),
+            aws_session_token: Some(Secret::new("token")),
+            ..Default::default()
+        }
+    }
+
+    /// Serves [`cred`] until `expires_at`, sending `request` with the `{token}` placeholder
+    /// replaced. Returns the response.
+    async fn request(expires_at: Option<SystemTime>, request: &str) -> String {
+        let server = CredentialServer::bind(cred(), expires_at).await.unwrap();
+        let uri = server.uri().unwrap();
+        let request = request.replace("{token}", server.token());
+        let server = spawn(server.serve());
+
+        let addr = uri
+            .strip_prefix("http://")
+            .and_then(|uri| uri.strip_suffix("/credentials"))
+            .unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        server.abort();
+
+        response
+    }
+
+    const AUTHORIZED_GET: &str = "GET /credentials HTTP/1.1\r\nAuthorization: {token}\r\n\r\n";
+
+    #[tokio::test]
+    async fn credentials_are_served_to_authorized_clients() {
+        let expires_at = humantime::parse_rfc3339("2100-01-01T00:00:00Z").unwrap();
+        let response = request(Some(expires_at), AUTHORIZED_GET).await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "AccessKeyId": "ASIAEXAMPLE",
+                "SecretAccessKey": "secret",
+                "Token": "token",
+                "Expiration": "2100-01-01T00:00:00Z",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn other_requests_are_refused() {
+        let expires_at = Some(SystemTime::now() + Duration::from_secs(60));
+        for (request_text, status) in [
+            ("GET /credentials HTTP/1.1\r\n\r\n", "403 Forbidden"),
+            (
+                "GET /credentials HTTP/1.1\r\nAuthorization: guess\r\n\r\n",
+                "403 Forbidden",
+            ),
+            (
+                "POST /credentials HTTP/1.1\r\nAuthorization: {token}\r\n\r\n",
+                "405 Method Not Allowed",
+            ),
+        ] {
+            let response = request(expires_at, request_text).await;
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+                "{response}"
+            );
+            assert!(!response.contains("secret"), "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_is_served_after_expiry() {
+        let expires_at = SystemTime::now() - Duration::from_secs(1);
+        let response = request(Some(expires_at), AUTHORIZED_GET).await;
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{response}"
+        );
+        assert!(response.ends_with("profile is locked"), "{response}");
+    }
+}
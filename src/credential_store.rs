@@ -0,0 +1,84 @@
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::PathBuf};
+
+use crate::{
+    app_dir::{app_dir, write_private_file},
+    aws_profile::{AwsCredentialData, AwsFile, AwsProfile, ProfileName},
+    error::{Error, Result},
+    vault::is_sealed,
+};
+
+/// Static credentials of profiles installed for `credential_process`, kept out of
+/// ~/.aws/credentials in a file only the current user can read.
+#[derive(Debug)]
+pub struct CredentialStore {
+    path: PathBuf,
+    profiles: BTreeMap<String, AwsCredentialData>,
+}
+
+impl CredentialStore {
+    pub fn open() -> Result<CredentialStore> {
+        let path = app_dir()?.join("credentials.json");
+        let profiles = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(CredentialStore { path, profiles })
+    }
+
+    pub fn get(&self, name: &ProfileName) -> Option<&AwsCredentialData> {
+        self.profiles.get(&name.to_string())
+    }
+
+    pub fn insert(&mut self, name: &ProfileName, cred: AwsCredentialData) {
+        self.profiles.insert(name.to_string(), cred);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        write_private_file(&self.path, &serde_json::to_vec_pretty(&self.profiles)?)
+    }
+}
+
+/// Looks up the static credentials of a profile, in the credential store first and then in
+/// ~/.aws/credentials.
+pub fn find_credentials(name: &ProfileName) -> Result<AwsCredentialData> {
+    let mut aws_file = AwsFile::open()?;
+    let profiles = aws_file.parse()?;
+    match profiles.iter().find(|profile| profile.name == *name) {
+        Some(profile) => credentials_of(profile),
+        None => {
+            let cred = CredentialStore::open()?.get(name).cloned();
+            unsealed(
+                name,
+                cred.ok_or_else(|| Error::NoCredentials(name.clone()))?,
+            )
+        }
+    }
+}
+
+/// Returns the static credentials of a parsed profile, from the credential store if the profile
+/// is installed for `credential_process`.
+pub fn credentials_of(profile: &AwsProfile) -> Result<AwsCredentialData> {
+    let name = &profile.name;
+    let cred = match CredentialStore::open()?.get(name) {
+        Some(cred) => cred.clone(),
+        None => profile
+            .data
+            .cred
+            .clone()
+            .ok_or_else(|| Error::NoCredentials(name.clone()))?,
+    };
+
+    unsealed(name, cred)
+}
+
+/// Fails if the credentials are the placeholders left in place of keys locked into the vault,
+/// which must never be served.
+fn unsealed(name: &ProfileName, cred: AwsCredentialData) -> Result<AwsCredentialData> {
+    if is_sealed(&cred) {
+        return Err(Error::Sealed(name.clone()));
+    }
+
+    Ok(cred)
+}
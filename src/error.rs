@@ -0,0 +1,184 @@
+use humantime::format_duration;
+use itertools::Itertools;
+use std::{io, path::PathBuf, time::Duration};
+
+use crate::{aws_profile::ProfileName, parse_error::ParseError, tier::Tier};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the library.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown profiles: {}", quoted(.0))]
+    UnknownProfiles(Vec<ProfileName>),
+
+    #[error("Unlocking production profiles cancelled by user")]
+    ProductionDeclined,
+
+    /// A confirmation of profiles below production, or of a command, was declined.
+    #[error("Unlocking cancelled by user")]
+    ConfirmationDeclined,
+
+    /// The phrase typed in differs from the `confirm` annotation of the profile.
+    #[error("the phrase typed in does not match the one required to unlock '{0}'")]
+    PhraseMismatch(ProfileName),
+
+    /// An annotation the profile needs to be unlocked is invalid, e.g. `max_duration`.
+    #[error("invalid annotation '{key}' of profile '{profile}': {reason}")]
+    InvalidAnnotation {
+        profile: ProfileName,
+        key: String,
+        reason: String,
+    },
+
+    /// Unlocking the profiles must be confirmed, but stdin is not a terminal to ask on.
+    #[error(
+        "profile {} requires confirmation, but stdin is not a terminal; approve {} with \
+         --yes-production or AWS_UNLOCK_YES_PRODUCTION",
+        quoted(.0),
+        if .0.len() == 1 { "it" } else { "them" },
+    )]
+    ConfirmationRequired(Vec<ProfileName>),
+
+    /// A command rule requires confirming the command, but stdin is not a terminal to ask on.
+    #[error(
+        "the command must be confirmed for profile {}, but stdin is not a terminal; approve {} \
+         with --yes-command or AWS_UNLOCK_YES_COMMAND",
+        quoted(.0),
+        if .0.len() == 1 { "it" } else { "them" },
+    )]
+    CommandConfirmationRequired(Vec<ProfileName>),
+
+    #[error(
+        "profile '{profile}' ({tier}) cannot be unlocked for more than {}",
+        format_duration(*.max)
+    )]
+    DurationNotAllowed {
+        profile: ProfileName,
+        tier: Tier,
+        max: Duration,
+    },
+
+    /// The tier of the profile only allows unlocking for certain programs. `program` is `None`
+    /// when unlocking for a duration.
+    #[error(
+        "profile '{profile}' ({tier}) cannot be unlocked {}",
+        program.as_ref().map_or_else(
+            || "without a command".to_string(),
+            |program| format!("for '{program}'"),
+        )
+    )]
+    CommandNotAllowed {
+        profile: ProfileName,
+        tier: Tier,
+        program: Option<String>,
+    },
+
+    /// The command rules of the profile deny the command. `command` is `None` when unlocking for
+    /// a duration.
+    #[error(
+        "profile '{profile}' cannot be unlocked {}: {reason}",
+        command.as_ref().map_or_else(
+            || "without a command".to_string(),
+            |command| format!("for '{command}'"),
+        )
+    )]
+    CommandDenied {
+        profile: ProfileName,
+        command: Option<String>,
+        reason: String,
+    },
+
+    /// The profiles were expected to be locked but are not, e.g. because another aws-unlock
+    /// process holds them unlocked.
+    #[error("profile {} is not locked", quoted(.0))]
+    NotLocked(Vec<ProfileName>),
+
+    /// The profiles are unlocked, but not by a running aws-unlock whose session could be joined.
+    #[error("profile {} is not unlocked by a running aws-unlock; there is no session to join", quoted(.0))]
+    NotJoinable(Vec<ProfileName>),
+
+    /// Some lines of the profiles are commented out and others are not.
+    #[error(
+        "profile {} is half-locked; run `aws-unlock repair` to lock it fully",
+        quoted(.0)
+    )]
+    Inconsistent(Vec<ProfileName>),
+
+    /// The profile has no active unlock lease.
+    #[error("profile '{0}' is locked")]
+    Locked(ProfileName),
+
+    #[error("no credentials found for profile '{0}'")]
+    NoCredentials(ProfileName),
+
+    #[error("credentials of profile '{0}' are sealed in the vault")]
+    Sealed(ProfileName),
+
+    #[error("failed to decrypt the vault: wrong passphrase?")]
+    WrongPassphrase,
+
+    #[error("{0}")]
+    Vault(String),
+
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    /// TOTP codes cannot be checked or the code is wrong.
+    #[error("TOTP: {0}")]
+    Totp(String),
+
+    /// The project settings file does not allow unlocking the profiles in its directory.
+    #[error(
+        "profile {} is not allowed in {} (see {})",
+        quoted(.profiles),
+        path.parent().unwrap_or(path).display(),
+        path.file_name().unwrap_or_default().to_string_lossy(),
+    )]
+    NotAllowedHere {
+        profiles: Vec<ProfileName>,
+        path: PathBuf,
+    },
+
+    #[error("invalid settings in {}: {message}", path.display())]
+    Settings { path: PathBuf, message: String },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// A state file in ~/.aws-unlock is corrupted.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// Process exit code for the error. Codes are distinct per kind so that scripts can tell
+    /// them apart; 1 is left for errors outside the library.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::UnknownProfiles(_) => 3,
+            Error::NotLocked(_) | Error::NotJoinable(_) => 4,
+            Error::Locked(_) => 5,
+            Error::ProductionDeclined
+            | Error::ConfirmationDeclined
+            | Error::PhraseMismatch(_)
+            | Error::ConfirmationRequired(_)
+            | Error::CommandConfirmationRequired(_) => 6,
+            Error::Parse(_) | Error::InvalidAnnotation { .. } => 7,
+            Error::NoCredentials(_) => 8,
+            Error::Sealed(_) | Error::WrongPassphrase | Error::Vault(_) => 9,
+            Error::Io(_) | Error::Json(_) => 10,
+            Error::Inconsistent(_) => 11,
+            Error::DurationNotAllowed { .. }
+            | Error::CommandNotAllowed { .. }
+            | Error::CommandDenied { .. } => 12,
+            Error::Settings { .. } => 13,
+            Error::NotAllowedHere { .. } => 14,
+            Error::Totp(_) => 15,
+        }
+    }
+}
+
+fn quoted(names: &[ProfileName]) -> String {
+    names.iter().map(|name| format!("'{name}'")).join(", ")
+}
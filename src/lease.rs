@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    app_dir::{app_dir, create_private_dir, encode_file_name, write_private_file},
+    aws_profile::ProfileName,
+    error::Result,
+};
+
+/// Record of an aws-unlock process holding a profile unlocked. `credential-process` only serves
+/// credentials of profiles with an active lease.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// Process that holds the lease.
+    pub pid: u32,
+
+    /// Seconds since the UNIX epoch when the lease expires. `None` if the lease lasts until the
+    /// holder exits, as in exec mode.
+    pub expires_at: Option<u64>,
+}
+
+impl Lease {
+    /// Acquires a lease on the profile for the current process.
+    pub fn acquire(name: &ProfileName, expires_at: Option<SystemTime>) -> Result<Lease> {
+        Lease::acquire_at(&lease_path(name)?, expires_at)
+    }
+
+    /// Releases the lease on the profile if it is held by the current process.
+    pub fn release(name: &ProfileName) -> Result<()> {
+        Lease::release_at(&lease_path(name)?)
+    }
+
+    /// Returns the lease on the profile if it has neither expired nor been orphaned by its
+    /// holder.
+    pub fn active(name: &ProfileName) -> Result<Option<Lease>> {
+        Lease::active_at(&lease_path(name)?)
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= to_unix_secs(SystemTime::now()))
+    }
+
+    fn acquire_at(path: &Path, expires_at: Option<SystemTime>) -> Result<Lease> {
+        let lease = Lease {
+            pid: process::id(),
+            expires_at: expires_at.map(to_unix_secs),
+        };
+        write_private_file(path, &serde_json::to_vec(&lease)?)?;
+
+        Ok(lease)
+    }
+
+    fn release_at(path: &Path) -> Result<()> {
+        match Lease::read(path)? {
+            Some(lease) if lease.pid == process::id() => Ok(fs::remove_file(path)?),
+            _ => Ok(()),
+        }
+    }
+
+    fn active_at(path: &Path) -> Result<Option<Lease>> {
+        Ok(Lease::read(path)?.filter(|lease| !lease.is_expired() && is_alive(lease.pid)))
+    }
+
+    fn read(path: &Path) -> Result<Option<Lease>> {
+        match fs::read(path) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn lease_path(name: &ProfileName) -> Result<PathBuf> {
+    let leases_dir = app_dir()?.join("leases");
+    create_private_dir(&leases_dir)?;
+
+    let file_name = encode_file_name(&name.to_string());
+    Ok(leases_dir.join(format!("{file_name}.json")))
+}
+
+fn to_unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process::Command};
+
+    fn lease_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("aws-unlock-lease-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn leases_are_active_until_released() {
+        let dir = lease_dir("release");
+        let path = dir.join("dev.json");
+        assert_eq!(Lease::active_at(&path).unwrap(), None);
+
+        // Exec mode leases last until the holder exits
+        let lease = Lease::acquire_at(&path, None).unwrap();
+        assert_eq!(Lease::active_at(&path).unwrap(), Some(lease));
+
+        Lease::release_at(&path).unwrap();
+        assert_eq!(Lease::active_at(&path).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leases_of_other_processes_are_not_released() {
+        let dir = lease_dir("other");
+        let path = dir.join("dev.json");
+        let lease = Lease {
+            pid: 1,
+            expires_at: None,
+        };
+        write_private_file(&path, &serde_json::to_vec(&lease).unwrap()).unwrap();
+
+        Lease::release_at(&path).unwrap();
+        assert_eq!(Lease::active_at(&path).unwrap(), Some(lease));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expired_and_orphaned_leases_are_inactive() {
+        let dir = lease_dir("inactive");
+        let path = dir.join("dev.json");
+        let expires_at = SystemTime::now() - Duration::from_secs(1);
+        Lease::acquire_at(&path, Some(expires_at)).unwrap();
+        assert_eq!(Lease::active_at(&path).unwrap(), None);
+
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let lease = Lease {
+            pid: child.id(),
+            expires_at: None,
+        };
+        write_private_file(&path, &serde_json::to_vec(&lease).unwrap()).unwrap();
+        assert_eq!(Lease::active_at(&path).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn joined_sessions_last_as_long_as_the_lease() {
+        let dir = lease_dir("join");
+        let path = dir.join("dev.json");
+        let expires_at = UNIX_EPOCH + Duration::from_secs(to_unix_secs(SystemTime::now()) + 60);
+        Lease::acquire_at(&path, Some(expires_at)).unwrap();
+        let lease = Lease::active_at(&path).unwrap().unwrap();
+        assert_eq!(lease.expires_at(), Some(expires_at));
+
+        Lease::acquire_at(&path, None).unwrap();
+        let lease = Lease::active_at(&path).unwrap().unwrap();
+        assert_eq!(lease.expires_at(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
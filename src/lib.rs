@@ -1,5 +1,23 @@
+pub mod annotation;
+pub mod app_dir;
+pub mod audit;
+pub mod aws_cache;
 pub mod aws_lock;
 pub mod aws_profile;
+pub mod backup;
+pub mod check;
+pub mod confirm;
+pub mod credential_process;
+pub mod credential_server;
+pub mod credential_store;
+pub mod error;
+pub mod lease;
 pub mod line_lexer;
 pub mod line_parser;
+pub mod parse_error;
+pub mod secret;
+pub mod settings;
+pub mod tier;
 pub mod timer;
+pub mod totp;
+pub mod vault;
@@ -1,4 +1,10 @@
-use anyhow::{anyhow, Ok, Result};
+use std::fmt;
+
+use crate::{
+    parse_error::{ParseError, ParseErrorKind},
+    secret::{redact_line, redact_value},
+    tier::Tier,
+};
 
 #[derive(Debug, Clone)]
 pub struct EntryLineLexer<'a> {
@@ -6,15 +12,124 @@ pub struct EntryLineLexer<'a> {
     index: usize,
 }
 
+/// Location of a line in the source file. Lines and columns are 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum EntryLine<'a> {
     Empty,
+
+    /// A whole-line comment with its `#` or `;` marker, as written.
     Comment(&'a str),
-    ProductionMarker,
+
+    /// `# aws-unlock: tier=<tier>`, or `# production`.
+    TierMarker(Tier),
+
+    /// `# aws-unlock: <key>=<value>` for keys other than `tier`.
+    Annotation {
+        key: &'a str,
+        value: &'a str,
+    },
+
+    /// Text between the brackets of a header, as written.
     Header(&'a str),
-    Option(&'a str, &'a str),
+
+    Option(OptionLine<'a>),
     LockedHeader(&'a str),
-    LockedOption(&'a str, &'a str),
+    LockedOption(OptionLine<'a>),
+
+    /// An indented line other than an option, such as a value continued from the line above,
+    /// as written with its indentation.
+    Continuation(&'a str),
+
+    /// An indented line other than an option after `#`, as written without the whitespace
+    /// before the `#`.
+    LockedContinuation(&'a str),
+}
+
+/// A `key = value` line.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct OptionLine<'a> {
+    pub key: &'a str,
+    pub value: &'a str,
+
+    /// Comment after the value with the whitespace before it, e.g. `  # note`. Empty if none.
+    pub inline_comment: &'a str,
+
+    /// Whitespace before the key, after the `# ` of a locked option. Options indented more than
+    /// the one above them are its sub-options, e.g. `max_concurrent_requests` under `s3 =`.
+    pub indent: &'a str,
+
+    /// The whole line as written without surrounding whitespace, including the `#` of a locked
+    /// option.
+    pub text: &'a str,
+}
+
+impl fmt::Debug for OptionLine<'_> {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        b.debug_struct("OptionLine")
+            .field("key", &self.key)
+            .field("value", &redact_value(self.key, self.value))
+            .field("inline_comment", &self.inline_comment)
+            .field("indent", &self.indent)
+            .finish()
+    }
+}
+
+impl fmt::Debug for EntryLine<'_> {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EntryLine::Empty => write!(b, "Empty"),
+            EntryLine::Comment(comment) => b.debug_tuple("Comment").field(comment).finish(),
+            EntryLine::TierMarker(tier) => b.debug_tuple("TierMarker").field(tier).finish(),
+            EntryLine::Annotation { key, value } => b
+                .debug_struct("Annotation")
+                .field("key", key)
+                .field("value", value)
+                .finish(),
+            EntryLine::Header(header) => b.debug_tuple("Header").field(header).finish(),
+            EntryLine::Option(option) => b.debug_tuple("Option").field(option).finish(),
+            EntryLine::LockedHeader(header) => b.debug_tuple("LockedHeader").field(header).finish(),
+            EntryLine::LockedOption(option) => b.debug_tuple("LockedOption").field(option).finish(),
+            EntryLine::Continuation(line) => b
+                .debug_tuple("Continuation")
+                .field(&redact_line(line))
+                .finish(),
+            EntryLine::LockedContinuation(line) => b
+                .debug_tuple("LockedContinuation")
+                .field(&redact_line(line))
+                .finish(),
+        }
+    }
+}
+
+impl EntryLine<'_> {
+    /// Short description for diagnostics. Never includes option values.
+    pub fn describe(&self) -> String {
+        match self {
+            EntryLine::Empty => "empty line".to_string(),
+            EntryLine::Comment(_) => "comment".to_string(),
+            EntryLine::TierMarker(tier) => format!("tier marker '{tier}'"),
+            EntryLine::Annotation { key, .. } => format!("annotation '{key}'"),
+            EntryLine::Header(header) => format!("header [{header}]"),
+            EntryLine::Option(option) => format!("option '{}'", option.key),
+            EntryLine::LockedHeader(header) => format!("locked header [{header}]"),
+            EntryLine::LockedOption(option) => format!("locked option '{}'", option.key),
+            EntryLine::Continuation(_) => "indented line".to_string(),
+            EntryLine::LockedContinuation(_) => "locked indented line".to_string(),
+        }
+    }
 }
 
 impl<'a> EntryLineLexer<'a> {
@@ -23,14 +138,23 @@ impl<'a> EntryLineLexer<'a> {
         Self { lines, index: 0 }
     }
 
-    pub fn tokenize(&mut self) -> Result<Vec<EntryLine<'a>>> {
+    pub fn tokenize(&mut self) -> Result<Vec<Spanned<EntryLine<'a>>>, ParseError> {
         let mut res = vec![];
         while let Some(line) = self.next_line() {
-            if line.trim().starts_with('#') {
-                res.push(tokenize_commented(line));
+            let trimmed = line.trim_start();
+            let span = Span {
+                line: self.index,
+                column: line.len() - trimmed.len() + 1,
+                len: trimmed.trim_end().len(),
+            };
+
+            let value = if trimmed.starts_with('#') {
+                tokenize_commented(trimmed).map_err(|kind| ParseError::new(kind, Some(span)))?
             } else {
-                res.push(tokenize_uncommented(line)?);
-            }
+                tokenize_uncommented(line)
+                    .ok_or_else(|| ParseError::new(ParseErrorKind::UnrecognizedLine, Some(span)))?
+            };
+            res.push(Spanned { value, span });
         }
 
         Ok(res)
@@ -45,47 +169,311 @@ impl<'a> EntryLineLexer<'a> {
     }
 }
 
-fn tokenize_commented(line: &str) -> EntryLine {
-    let trimmed = line.trim()[1..].trim();
-    if trimmed == "production" {
-        EntryLine::ProductionMarker
-    } else if trimmed.starts_with('[') && trimmed.ends_with(']') {
-        // Header
-        EntryLine::LockedHeader(&trimmed[1..trimmed.len() - 1])
-    } else if trimmed.contains('=') {
-        // Option
-        let [key, value]: [&str; 2] = trimmed
-            .splitn(2, '=')
-            .collect::<Vec<_>>()
-            .try_into()
-            .expect("should always be splitted to two entries");
-        let key = key.trim_end();
-        let value = value.trim_start();
-        EntryLine::LockedOption(key, value)
+fn tokenize_commented(line: &str) -> Result<EntryLine<'_>, ParseErrorKind> {
+    let line = line.trim();
+    let trimmed = line[1..].trim();
+    let line = if trimmed == "production" {
+        EntryLine::TierMarker(Tier::Production)
+    } else if let Some((key, value)) = strip_annotation(trimmed) {
+        if key.eq_ignore_ascii_case("tier") {
+            let tier = value.parse().map_err(|()| ParseErrorKind::UnknownTier {
+                tier: value.to_string(),
+            })?;
+            EntryLine::TierMarker(tier)
+        } else {
+            // Invalid values are reported by `check`, so that they do not block other profiles
+            EntryLine::Annotation { key, value }
+        }
+    } else if trimmed.starts_with(['#', ';']) {
+        // A comment commented out once more, e.g. `# #output = json` in a locked section
+        EntryLine::Comment(line)
+    } else if let Some(header) = strip_brackets(trimmed) {
+        EntryLine::LockedHeader(header)
+    } else if let Some(option) = tokenize_option(strip_lock_prefix(line)) {
+        EntryLine::LockedOption(OptionLine {
+            text: line,
+            ..option
+        })
+    } else if strip_lock_prefix(line).starts_with(char::is_whitespace) {
+        EntryLine::LockedContinuation(line.trim_end())
     } else {
         // Simple Comment
-        let start = if line.trim().starts_with("# ") { 2 } else { 1 };
-        EntryLine::Comment(&line[start..])
-    }
-}
-
-fn tokenize_uncommented(line: &str) -> Result<EntryLine> {
-    if line.starts_with('[') && line.ends_with(']') {
-        // Header
-        Ok(EntryLine::Header(&line[1..line.len() - 1]))
-    } else if line.contains('=') {
-        // Option
-        let [key, value]: [&str; 2] = line
-            .splitn(2, '=')
-            .collect::<Vec<_>>()
-            .try_into()
-            .expect("should always be splitted to two entries");
-        let key = key.trim_end();
-        let value = value.trim_start();
-        Ok(EntryLine::Option(key, value))
-    } else if line.trim() == "" {
-        Ok(EntryLine::Empty)
+        EntryLine::Comment(line)
+    };
+
+    Ok(line)
+}
+
+/// Removes the `# ` that aws-unlock puts before locked lines, or just the `#` if no space
+/// follows it, keeping any further indentation.
+pub fn strip_lock_prefix(line: &str) -> &str {
+    let line = line.trim_start();
+    let line = line.strip_prefix('#').unwrap_or(line);
+    line.strip_prefix(' ').unwrap_or(line)
+}
+
+/// Length of the whitespace before the text of a line written as when unlocked.
+pub fn indent_len(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Returns true if the comment line would be read as a locked option or header, such as
+/// `#output = json`.
+pub fn is_locked_line(comment: &str) -> bool {
+    let comment = comment.trim();
+    comment.starts_with('#')
+        && matches!(
+            tokenize_commented(comment),
+            Ok(EntryLine::LockedOption(_) | EntryLine::LockedHeader(_))
+        )
+}
+
+/// Returns the comment without the `#` added by locking if it would then be read as a locked option
+/// or header, e.g. `#output = json` for `# #output = json`.
+pub fn strip_locked_comment(comment: &str) -> Option<&str> {
+    comment
+        .trim()
+        .strip_prefix('#')
+        .map(str::trim_start)
+        .filter(|inner| is_locked_line(inner))
+}
+
+/// Splits `aws-unlock: <key>=<value>` into the key and the value.
+fn strip_annotation(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.strip_prefix("aws-unlock:")?.split_once('=')?;
+    Some((key.trim(), value.trim()))
+}
+
+fn tokenize_uncommented(line: &str) -> Option<EntryLine<'_>> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        Some(EntryLine::Empty)
+    } else if trimmed.starts_with(';') {
+        Some(EntryLine::Comment(trimmed))
+    } else if let Some(header) = strip_brackets(trimmed) {
+        Some(EntryLine::Header(header))
+    } else if let Some(option) = tokenize_option(line) {
+        Some(EntryLine::Option(option))
+    } else if line.starts_with(char::is_whitespace) {
+        Some(EntryLine::Continuation(line.trim_end()))
     } else {
-        Err(anyhow!("unexpected line: {:?}", line))
+        None
+    }
+}
+
+fn strip_brackets(line: &str) -> Option<&str> {
+    line.strip_prefix('[')?.strip_suffix(']')
+}
+
+/// Splits `key = value` into its parts, recording the whitespace before the key. As in the AWS
+/// CLI, `#` or `;` after whitespace starts an inline comment.
+fn tokenize_option(line: &str) -> Option<OptionLine<'_>> {
+    let trimmed = line.trim();
+    let indent = &line[..line.len() - line.trim_start().len()];
+    let (key, rest) = trimmed.split_once('=')?;
+    let rest = rest.trim_start();
+
+    let mut prev_is_whitespace = false;
+    let comment_start = rest.char_indices().find_map(|(index, c)| {
+        let is_comment = prev_is_whitespace && (c == '#' || c == ';');
+        prev_is_whitespace = c.is_whitespace();
+        is_comment.then_some(index)
+    });
+    let (value, inline_comment) = match comment_start {
+        Some(index) => {
+            let value = rest[..index].trim_end();
+            (value, &rest[value.len()..])
+        }
+        None => (rest, ""),
+    };
+
+    Some(OptionLine {
+        key: key.trim_end(),
+        value,
+        inline_comment,
+        indent,
+        text: trimmed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenize(contents: &str) -> Vec<EntryLine<'_>> {
+        EntryLineLexer::new(contents)
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|line| line.value)
+            .collect()
+    }
+
+    fn option<'a>(
+        key: &'a str,
+        value: &'a str,
+        inline_comment: &'a str,
+        text: &'a str,
+    ) -> OptionLine<'a> {
+        OptionLine {
+            key,
+            value,
+            inline_comment,
+            indent: "",
+            text,
+        }
+    }
+
+    #[test]
+    fn tokenizes_tier_markers() {
+        let lines = tokenize(
+            "# production\n\
+             # aws-unlock: tier=break-glass\n\
+             #aws-unlock:tier = Staging\n",
+        );
+        assert_eq!(
+            lines,
+            [
+                EntryLine::TierMarker(Tier::Production),
+                EntryLine::TierMarker(Tier::BreakGlass),
+                EntryLine::TierMarker(Tier::Staging),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_annotations() {
+        let lines = tokenize(
+            "# aws-unlock: owner = infra\n\
+             # aws-unlock: max_duration=not-a-duration\n",
+        );
+        assert_eq!(
+            lines,
+            [
+                EntryLine::Annotation {
+                    key: "owner",
+                    value: "infra"
+                },
+                // Invalid values are left for `check` to report
+                EntryLine::Annotation {
+                    key: "max_duration",
+                    value: "not-a-duration"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_tiers() {
+        let err = EntryLineLexer::new("# aws-unlock: tier=secret")
+            .tokenize()
+            .unwrap_err();
+        assert!(err.to_string().contains("secret"), "{err}");
+    }
+
+    #[test]
+    fn tokenizes_options_and_locked_lines() {
+        let lines = tokenize(
+            "[profile a]\n\
+             region = us-east-1  # main region\n\
+             \n\
+             # [profile b]\n\
+             # output=json\n\
+             ; note\n\
+             # just a note\n",
+        );
+        assert_eq!(
+            lines,
+            [
+                EntryLine::Header("profile a"),
+                EntryLine::Option(option(
+                    "region",
+                    "us-east-1",
+                    "  # main region",
+                    "region = us-east-1  # main region"
+                )),
+                EntryLine::Empty,
+                EntryLine::LockedHeader("profile b"),
+                EntryLine::LockedOption(option("output", "json", "", "# output=json")),
+                EntryLine::Comment("; note"),
+                EntryLine::Comment("# just a note"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_indented_lines() {
+        let lines = tokenize(
+            "s3 =\n  max_concurrent_requests = 20\n    continued\n\
+             #   endpoint_url = http://localhost\n#     continued\n",
+        );
+        assert_eq!(
+            lines,
+            [
+                EntryLine::Option(option("s3", "", "", "s3 =")),
+                EntryLine::Option(OptionLine {
+                    indent: "  ",
+                    ..option(
+                        "max_concurrent_requests",
+                        "20",
+                        "",
+                        "max_concurrent_requests = 20"
+                    )
+                }),
+                EntryLine::Continuation("    continued"),
+                EntryLine::LockedOption(OptionLine {
+                    indent: "  ",
+                    ..option(
+                        "endpoint_url",
+                        "http://localhost",
+                        "",
+                        "#   endpoint_url = http://localhost"
+                    )
+                }),
+                EntryLine::LockedContinuation("#     continued"),
+            ]
+        );
+        assert_eq!(strip_lock_prefix("#     continued"), "    continued");
+    }
+
+    #[test]
+    fn comments_commented_out_again_stay_comments() {
+        assert_eq!(
+            tokenize("# #output = json"),
+            [EntryLine::Comment("# #output = json")]
+        );
+        assert!(is_locked_line("#output = json"));
+        assert!(!is_locked_line("# a note"));
+        assert!(!is_locked_line("output = json"));
+        assert_eq!(
+            strip_locked_comment("# #output = json"),
+            Some("#output = json")
+        );
+        assert_eq!(strip_locked_comment("# a note"), None);
+    }
+
+    #[test]
+    fn values_keep_hashes_not_after_whitespace() {
+        let lines = tokenize("key = a#b ;c");
+        assert_eq!(
+            lines,
+            [EntryLine::Option(option(
+                "key",
+                "a#b",
+                " ;c",
+                "key = a#b ;c"
+            ))]
+        );
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let lines = tokenize("aws_secret_access_key = hunter2");
+        assert!(!format!("{lines:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn rejects_unrecognized_lines() {
+        assert!(EntryLineLexer::new("not an option").tokenize().is_err());
     }
 }
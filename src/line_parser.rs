@@ -1,25 +1,256 @@
-use anyhow::{anyhow, bail, Ok, Result};
-use std::{collections::HashMap, iter::from_fn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    iter::from_fn,
+};
 
-use crate::line_lexer::EntryLine;
+use crate::{
+    annotation::Annotations,
+    line_lexer::{
+        indent_len, strip_lock_prefix, strip_locked_comment, EntryLine, OptionLine, Span, Spanned,
+    },
+    parse_error::{ParseError, ParseErrorKind},
+    secret::{redact_line, redact_value, SECRET_KEYS},
+    tier::Tier,
+};
+
+type Result<T> = std::result::Result<T, ParseError>;
 
 #[derive(Debug, Clone)]
 pub struct EntryLineParser<'a> {
-    lines: Vec<EntryLine<'a>>,
+    lines: Vec<Spanned<EntryLine<'a>>>,
     index: usize,
 }
 
-#[derive(Debug, Clone)]
+/// Whether the lines of a section are commented out by aws-unlock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LockState {
+    Unlocked,
+    Locked,
+
+    /// Some lines are commented out and others are not, e.g. after a hand edit. Such a section
+    /// is neither safely locked nor usable.
+    Inconsistent,
+}
+
+impl LockState {
+    pub fn new(is_locked: bool) -> Self {
+        if is_locked {
+            LockState::Locked
+        } else {
+            LockState::Unlocked
+        }
+    }
+
+    pub fn is_locked(self) -> bool {
+        self == LockState::Locked
+    }
+}
+
+impl fmt::Display for LockState {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockState::Unlocked => write!(b, "unlocked"),
+            LockState::Locked => write!(b, "locked"),
+            LockState::Inconsistent => write!(b, "inconsistent"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Entry {
     pub comments: Vec<String>,
-    pub is_production: bool,
+    pub tier: Tier,
+    pub annotations: Annotations,
+
+    /// Whether the header is commented out.
     pub is_locked: bool,
+
+    /// Text between the brackets of the header, as written.
     pub header: String,
+
+    /// Location of the header line.
+    pub header_span: Span,
+
+    /// Values keyed by lowercase keys, since keys are case-insensitive.
     pub values: HashMap<String, String>,
+
+    /// Location of each option line.
+    pub value_spans: HashMap<String, Span>,
+
+    /// Every option line in the order they are written, including repeated keys.
+    pub options: Vec<RawOption>,
+
+    /// Option lines whose lock state differs from the header's. They are read as values like
+    /// the others.
+    pub inconsistent_spans: Vec<Span>,
+
+    pub spelling: Spelling,
+}
+
+/// An option line as written, for options that are written back as they were read.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RawOption {
+    /// Lowercase key.
+    pub key: String,
+
+    pub value: String,
+
+    /// The line as written when unlocked, e.g. `Mfa_Serial = arn:aws:iam::123:mfa/me  # note`.
+    pub text: String,
+
+    /// Comment lines before the option.
+    pub comments: Vec<String>,
+
+    /// Lines indented under the option, as written when unlocked.
+    pub children: Vec<String>,
+}
+
+impl fmt::Debug for RawOption {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        b.debug_struct("RawOption")
+            .field("key", &self.key)
+            .field("value", &redact_value(&self.key, &self.value))
+            .field("text", &redact_line(&self.text))
+            .field("comments", &self.comments)
+            .field("children", &self.children)
+            .finish()
+    }
+}
+
+/// How a section is written in the file beyond its values, so that it can be written back the
+/// same way.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Spelling {
+    /// Text between the brackets of the header, e.g. ` profile  foo `.
+    pub header: Option<String>,
+
+    /// Keys as written, by lowercase key. Only keys not written in lowercase are recorded.
+    pub keys: BTreeMap<String, String>,
+
+    /// Comments after values with the whitespace before them, by lowercase key.
+    pub inline_comments: BTreeMap<String, String>,
+
+    /// Comment lines inside the section, by the lowercase key of the option they precede.
+    pub comments: BTreeMap<String, Vec<String>>,
+
+    /// Comment lines after the last option, such as options commented out by hand.
+    pub trailing_comments: Vec<String>,
+
+    /// Lines indented under an option, such as the sub-options of `s3 =`, by the lowercase key of
+    /// the option. They are kept as written when unlocked, indentation included.
+    pub children: BTreeMap<String, Vec<String>>,
+}
+
+impl Spelling {
+    /// Adds the spelling of a later section of the same profile, which takes precedence.
+    pub fn merge(&mut self, other: Spelling) {
+        self.keys.extend(other.keys);
+        self.inline_comments.extend(other.inline_comments);
+        for (key, comments) in other.comments {
+            self.comments.entry(key).or_default().extend(comments);
+        }
+        self.trailing_comments.extend(other.trailing_comments);
+        self.children.extend(other.children);
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        let values: HashMap<_, _> = self
+            .values
+            .iter()
+            .map(|(key, value)| (key, redact_value(key, value)))
+            .collect();
+        b.debug_struct("Entry")
+            .field("comments", &self.comments)
+            .field("tier", &self.tier)
+            .field("annotations", &self.annotations)
+            .field("is_locked", &self.is_locked)
+            .field("header", &self.header)
+            .field("header_span", &self.header_span)
+            .field("values", &values)
+            .field("value_spans", &self.value_spans)
+            .field("options", &self.options)
+            .field("inconsistent_spans", &self.inconsistent_spans)
+            .field("spelling", &self.spelling)
+            .finish()
+    }
+}
+
+impl Entry {
+    pub fn lock_state(&self) -> LockState {
+        if self.inconsistent_spans.is_empty() {
+            LockState::new(self.is_locked)
+        } else {
+            LockState::Inconsistent
+        }
+    }
+}
+
+/// Options of a section collected by [`EntryLineParser::parse_values`].
+#[derive(Default)]
+struct Values {
+    values: HashMap<String, String>,
+    value_spans: HashMap<String, Span>,
+    options: Vec<RawOption>,
+    inconsistent_spans: Vec<Span>,
+    spelling: Spelling,
+}
+
+impl Values {
+    fn insert(
+        &mut self,
+        option: &OptionLine,
+        is_line_locked: bool,
+        comments: Vec<String>,
+        span: Span,
+    ) {
+        let key = option.key.to_ascii_lowercase();
+        if key != option.key {
+            self.spelling
+                .keys
+                .insert(key.clone(), option.key.to_string());
+        }
+        if !option.inline_comment.is_empty() {
+            let comment = option.inline_comment.to_string();
+            self.spelling.inline_comments.insert(key.clone(), comment);
+        }
+        if !comments.is_empty() {
+            self.spelling.comments.insert(key.clone(), comments.clone());
+        }
+        let text = if is_line_locked {
+            strip_lock_prefix(option.text).trim_start()
+        } else {
+            option.text
+        };
+        self.options.push(RawOption {
+            key: key.clone(),
+            value: option.value.to_string(),
+            text: text.to_string(),
+            comments,
+            children: vec![],
+        });
+        self.value_spans.insert(key.clone(), span);
+        self.spelling.children.remove(&key);
+        self.values.insert(key, option.value.to_string());
+    }
+}
+
+/// The option that indented lines belong to while collecting the options of a section.
+enum Parent {
+    /// An option read as a value, by lowercase key, and the length of its indentation.
+    Value(String, usize),
+
+    /// An option commented out by hand, which is kept as a comment along with its indented lines.
+    Comment(usize),
 }
 
 impl<'a> EntryLineParser<'a> {
-    pub fn new(lines: Vec<EntryLine<'a>>) -> Self {
+    pub fn new(lines: Vec<Spanned<EntryLine<'a>>>) -> Self {
         Self { lines, index: 0 }
     }
 
@@ -34,50 +265,52 @@ impl<'a> EntryLineParser<'a> {
         }
 
         let mut all_comments = vec![];
-        let (comments, is_production) = self.parse_is_production()?;
+        let (comments, tier, annotations) = self.parse_markers()?;
         all_comments.extend(comments);
 
         let (comments, is_locked) = self.parse_is_locked()?;
         all_comments.extend(comments);
 
-        let (comments, header) = self.parse_header(is_locked)?;
+        let (comments, header, header_span) = self.parse_header(is_locked)?;
         all_comments.extend(comments);
 
-        let (comments, values) = self.parse_values(is_locked)?;
-        all_comments.extend(comments);
+        let mut values = self.parse_values(is_locked)?;
+        values.spelling.header = Some(header.clone());
 
         Ok(Some(Entry {
             comments: all_comments,
-            is_production,
+            tier,
+            annotations,
             is_locked,
             header,
-            values,
+            header_span,
+            values: values.values,
+            value_spans: values.value_spans,
+            options: values.options,
+            inconsistent_spans: values.inconsistent_spans,
+            spelling: values.spelling,
         }))
     }
 
-    fn parse_is_production(&mut self) -> Result<(Vec<String>, bool)> {
+    /// Collects the tier marker and annotations above the header, along with comments.
+    fn parse_markers(&mut self) -> Result<(Vec<String>, Tier, Annotations)> {
         let mut comments = vec![];
+        let mut tier = Tier::Dev;
+        let mut annotations = Annotations::default();
         while let Some(line) = self.peek_line() {
-            match line {
-                EntryLine::Empty => {
-                    self.next_line().unwrap();
-                    continue;
+            match *line {
+                EntryLine::Empty => {}
+                EntryLine::Comment(comment) | EntryLine::LockedContinuation(comment) => {
+                    comments.push(comment.to_string())
                 }
-                EntryLine::Comment(comment) => {
-                    let comment = comment.to_string();
-                    self.next_line().unwrap();
-                    comments.push(comment);
-                    continue;
-                }
-                EntryLine::ProductionMarker => {
-                    self.next_line().unwrap();
-                    return Ok((comments, true));
-                }
-                _ => return Ok((comments, false)),
+                EntryLine::TierMarker(marker) => tier = marker,
+                EntryLine::Annotation { key, value } => annotations.insert(key, value),
+                _ => return Ok((comments, tier, annotations)),
             }
+            self.next_line().unwrap();
         }
 
-        Err(anyhow!("unexpected EOF while scanning is_production"))
+        Err(self.unexpected_eof("a profile header"))
     }
 
     fn parse_is_locked(&mut self) -> Result<(Vec<String>, bool)> {
@@ -88,24 +321,26 @@ impl<'a> EntryLineParser<'a> {
                     self.next_line().unwrap();
                     continue;
                 }
-                EntryLine::Comment(comment) => {
+                EntryLine::Comment(comment) | EntryLine::LockedContinuation(comment) => {
                     let comment = comment.to_string();
                     self.next_line().unwrap();
                     comments.push(comment);
                     continue;
                 }
-                EntryLine::ProductionMarker => bail!("unexpected production marker"),
-                EntryLine::LockedHeader(_) | EntryLine::LockedOption(_, _) => {
+                EntryLine::TierMarker(_)
+                | EntryLine::Annotation { .. }
+                | EntryLine::Continuation(_) => return Err(self.unexpected("a profile header")),
+                EntryLine::LockedHeader(_) | EntryLine::LockedOption(_) => {
                     return Ok((comments, true))
                 }
-                EntryLine::Header(_) | EntryLine::Option(_, _) => return Ok((comments, false)),
+                EntryLine::Header(_) | EntryLine::Option(_) => return Ok((comments, false)),
             }
         }
 
-        Err(anyhow!("unexpected EOF while scanning is_locked"))
+        Err(self.unexpected_eof("a profile header"))
     }
 
-    fn parse_header(&mut self, is_locked: bool) -> Result<(Vec<String>, String)> {
+    fn parse_header(&mut self, is_locked: bool) -> Result<(Vec<String>, String, Span)> {
         let mut comments = vec![];
         while let Some(line) = self.peek_line() {
             match line {
@@ -113,61 +348,168 @@ impl<'a> EntryLineParser<'a> {
                     self.next_line().unwrap();
                     continue;
                 }
-                EntryLine::Comment(comment) => {
+                EntryLine::Comment(comment) | EntryLine::LockedContinuation(comment) => {
                     let comment = comment.to_string();
                     self.next_line().unwrap();
                     comments.push(comment);
                     continue;
                 }
-                EntryLine::ProductionMarker => {
-                    bail!("unexpected production marker while parsing header")
-                }
                 EntryLine::Header(header) if !is_locked => {
                     let header = header.to_string();
-                    self.next_line().unwrap();
-                    return Ok((comments, header));
+                    let span = self.next_span();
+                    return Ok((comments, header, span));
                 }
                 EntryLine::LockedHeader(header) if is_locked => {
                     let header = header.to_string();
-                    self.next_line().unwrap();
-                    return Ok((comments, header));
+                    let span = self.next_span();
+                    return Ok((comments, header, span));
                 }
-                _ => bail!("unexpected line while scanning header"),
+                _ if is_locked => return Err(self.unexpected("a locked header")),
+                _ => return Err(self.unexpected("a header")),
             }
         }
 
-        Err(anyhow!("unexpected EOF while scanning header"))
+        Err(self.unexpected_eof("a header"))
     }
 
-    fn parse_values(&mut self, is_locked: bool) -> Result<(Vec<String>, HashMap<String, String>)> {
-        let mut values = HashMap::new();
+    /// Collects options until the next section. Each line is locked or not on its own; lines
+    /// that disagree with `is_locked`, the lock state of the header, are recorded.
+    ///
+    /// In an unlocked section, a commented out option is only taken as half-locking if it is a
+    /// secret or its key is also set uncommented. Others, such as `#output = json`, are kept as
+    /// comments, unless the section is half-locked anyway, in which case every commented out
+    /// option is read so that the section can be locked fully.
+    ///
+    /// Lines indented more than the option above them belong to it, as in the AWS CLI, and are
+    /// kept as they are written rather than read as values.
+    fn parse_values(&mut self, is_locked: bool) -> Result<Values> {
+        let section_lines: Vec<_> = self.lines[self.index..]
+            .iter()
+            .map(|line| &line.value)
+            .take_while(|line| {
+                matches!(
+                    line,
+                    EntryLine::Empty
+                        | EntryLine::Comment(_)
+                        | EntryLine::Option(_)
+                        | EntryLine::LockedOption(_)
+                        | EntryLine::Continuation(_)
+                        | EntryLine::LockedContinuation(_)
+                )
+            })
+            .collect();
+        let uncommented_keys: HashSet<_> = section_lines
+            .iter()
+            .filter_map(|line| match line {
+                EntryLine::Option(option) if option.indent.is_empty() => {
+                    Some(option.key.to_ascii_lowercase())
+                }
+                _ => None,
+            })
+            .collect();
+        let is_half_locking = |option: &OptionLine| {
+            let key = option.key.to_ascii_lowercase();
+            SECRET_KEYS.contains(&key.as_str()) || uncommented_keys.contains(&key)
+        };
+        let is_half_locked = !is_locked
+            && section_lines.iter().any(|line| {
+                matches!(line, EntryLine::LockedOption(option)
+                    if option.indent.is_empty() && is_half_locking(option))
+            });
+        // Lines commented out in a half-locked section are read as if the section were locked
+        let is_read_locked = is_locked || is_half_locked;
+
+        let mut values = Values::default();
+        let mut comments = vec![];
+        let mut parent = None;
         while let Some(line) = self.peek_line() {
-            match line {
+            if let Some(child) = child_line(line, is_read_locked) {
+                match &parent {
+                    Some(Parent::Value(key, indent)) if child.indent > *indent => {
+                        let span = self.next_span();
+                        if child.is_inconsistent {
+                            values.inconsistent_spans.push(span);
+                        }
+                        values
+                            .spelling
+                            .children
+                            .entry(key.clone())
+                            .or_default()
+                            .push(child.text.clone());
+                        if let Some(option) = values.options.last_mut() {
+                            option.children.push(child.text);
+                        }
+                        continue;
+                    }
+                    Some(Parent::Comment(indent))
+                        if child.is_commented && child.indent > *indent =>
+                    {
+                        comments.push(child.text);
+                        self.next_line().unwrap();
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
+            let (option, is_line_locked) = match line {
                 EntryLine::Empty => {
                     self.next_line().unwrap();
                     continue;
                 }
-                EntryLine::Option(key, value) if !is_locked => {
-                    let key = key.to_string();
-                    let value = value.to_string();
+                // Comments followed by another option belong to this section, and so do options
+                // commented out by hand in a locked section, such as `# #output = json`
+                EntryLine::Comment(comment)
+                    if self.is_option_ahead()
+                        || (is_locked && strip_locked_comment(comment).is_some()) =>
+                {
+                    comments.push(comment.to_string());
                     self.next_line().unwrap();
-                    values.insert(key, value);
+                    continue;
+                }
+                EntryLine::Option(option) => (option.clone(), false),
+                EntryLine::LockedOption(option) if !is_read_locked && !is_half_locking(option) => {
+                    parent = Some(Parent::Comment(option.indent.len()));
+                    comments.push(option.text.to_string());
+                    self.next_line().unwrap();
+                    continue;
                 }
-                EntryLine::LockedOption(key, value) => {
-                    let key = key.to_string();
-                    let value = value.to_string();
+                EntryLine::LockedOption(option) => (option.clone(), true),
+                // Hand-written comments that happen to be indented
+                EntryLine::LockedContinuation(comment) if self.is_option_ahead() || is_locked => {
+                    comments.push(comment.to_string());
                     self.next_line().unwrap();
-                    values.insert(key, value);
+                    continue;
                 }
-                EntryLine::ProductionMarker
+                EntryLine::Continuation(_) => return Err(self.unexpected("an option")),
+                EntryLine::TierMarker(_)
+                | EntryLine::Annotation { .. }
                 | EntryLine::Comment(_)
+                | EntryLine::LockedContinuation(_)
                 | EntryLine::Header(_)
-                | EntryLine::LockedHeader(_) => return Ok((vec![], values)),
-                _ => bail!("unexpected line while scanning values"),
+                | EntryLine::LockedHeader(_) => break,
+            };
+
+            let span = self.next_span();
+            if is_line_locked != is_locked {
+                values.inconsistent_spans.push(span);
             }
+            let key = option.key.to_ascii_lowercase();
+            parent = Some(Parent::Value(key, option.indent.len()));
+            values.insert(&option, is_line_locked, std::mem::take(&mut comments), span);
         }
+        values.spelling.trailing_comments = comments;
+
+        Ok(values)
+    }
 
-        Ok((vec![], values))
+    /// Returns true if the next line other than comments and empty lines is an option.
+    fn is_option_ahead(&self) -> bool {
+        self.lines[self.index..]
+            .iter()
+            .map(|line| &line.value)
+            .find(|line| !matches!(line, EntryLine::Comment(_) | EntryLine::Empty))
+            .is_some_and(|line| matches!(line, EntryLine::Option(_) | EntryLine::LockedOption(_)))
     }
 
     fn skip_empty_line(&mut self) {
@@ -176,19 +518,96 @@ impl<'a> EntryLineParser<'a> {
         }
     }
 
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let line = &self.lines[self.index];
+        ParseError::new(
+            ParseErrorKind::Unexpected {
+                expected: expected.to_string(),
+                found: line.value.describe(),
+            },
+            Some(line.span),
+        )
+    }
+
+    fn unexpected_eof(&self, expected: &str) -> ParseError {
+        ParseError::new(
+            ParseErrorKind::UnexpectedEof {
+                expected: expected.to_string(),
+            },
+            None,
+        )
+    }
+
     fn is_finished(&self) -> bool {
         self.index == self.lines.len()
     }
 
-    fn peek_line(&self) -> Option<&EntryLine> {
-        self.lines.get(self.index)
+    fn peek_line(&self) -> Option<&EntryLine<'a>> {
+        self.lines.get(self.index).map(|line| &line.value)
     }
 
-    fn next_line(&mut self) -> Option<&EntryLine> {
-        let res = self.lines.get(self.index);
+    fn next_line(&mut self) -> Option<&EntryLine<'_>> {
+        let res = self.lines.get(self.index).map(|line| &line.value);
         if self.index < self.lines.len() {
             self.index += 1;
         }
         res
     }
+
+    fn next_span(&mut self) -> Span {
+        let span = self.lines[self.index].span;
+        self.next_line().unwrap();
+        span
+    }
+}
+
+/// A line that can be indented under an option.
+struct ChildLine {
+    /// The line as written when unlocked, with its indentation.
+    text: String,
+
+    /// Length of the whitespace before the option or value, after any `#`.
+    indent: usize,
+
+    /// Whether the line is commented out, by locking or by hand.
+    is_commented: bool,
+
+    /// Whether the line is uncommented in a locked section.
+    is_inconsistent: bool,
+}
+
+/// Reads a line that can be indented under an option. In a locked section, lines commented out
+/// by hand are commented out once more, e.g. `# #  max_concurrent_requests = 20`, while in an
+/// unlocked one they are kept as comments.
+fn child_line(line: &EntryLine, is_locked: bool) -> Option<ChildLine> {
+    let uncommented = |text: String| ChildLine {
+        indent: indent_len(&text),
+        text,
+        is_commented: false,
+        is_inconsistent: is_locked,
+    };
+    let commented = |text: &str, inner: &str| ChildLine {
+        text: text.to_string(),
+        indent: indent_len(strip_lock_prefix(inner)),
+        is_commented: true,
+        is_inconsistent: false,
+    };
+    match line {
+        EntryLine::Option(option) => Some(uncommented(format!("{}{}", option.indent, option.text))),
+        EntryLine::Continuation(line) => Some(uncommented(line.to_string())),
+        EntryLine::LockedOption(OptionLine { text: line, .. })
+        | EntryLine::LockedContinuation(line) => {
+            let text = if is_locked {
+                strip_lock_prefix(line)
+            } else {
+                line
+            };
+            Some(commented(text, line))
+        }
+        EntryLine::Comment(comment) if is_locked => {
+            let inner = strip_lock_prefix(comment);
+            inner.starts_with('#').then(|| commented(inner, inner))
+        }
+        _ => None,
+    }
 }
@@ -1,22 +1,68 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use aws_unlock::{
-    aws_lock::AwsLockGuard,
-    aws_profile::{AwsFile, AwsProfile, ProfileName},
+    aws_cache::CachePolicy,
+    aws_lock::{join_sessions, lock_caches, AwsLockGuard, Purpose, UnlockChecks},
+    aws_profile::{AwsFile, AwsProfile, AwsProfileData, DuplicatePolicy, ProfileName},
+    check::check,
+    confirm::{Confirmer, TerminalConfirmer},
+    credential_process::{credential_process_output, install},
+    credential_server::CredentialServer,
+    credential_store::credentials_of,
+    error::Error,
+    lease::Lease,
+    line_parser::LockState,
+    settings::{ColorChoice, OutputFormat, Settings},
+    tier::Tier,
     timer::ObservableTimer,
+    totp::{render_qr, Totp},
+    vault::{is_sealed, LockBackend, Vault},
 };
 use clap::{CommandFactory, Parser};
+use glob::Pattern;
+use humantime::format_rfc3339_seconds;
 use itertools::Itertools;
+use regex::Regex;
+use serde_json::{json, Value};
 use std::{
     collections::HashMap,
+    env,
     io::{stdout, Write},
     process::ExitCode,
-    time::Duration,
+    sync::mpsc,
+    time::{Duration, SystemTime},
 };
-use tokio::process::Command;
+use tokio::{
+    process::Command,
+    spawn,
+    task::{spawn_blocking, JoinHandle},
+};
+
+/// Environment variables the AWS SDKs resolve credentials from before the container credentials
+/// endpoint, or instead of the one set by `serve`: a relative URI takes precedence over a full one.
+const SHADOWING_ENVVARS: [&str; 8] = [
+    "AWS_PROFILE",
+    "AWS_DEFAULT_PROFILE",
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "AWS_SESSION_TOKEN",
+    "AWS_WEB_IDENTITY_TOKEN_FILE",
+    "AWS_ROLE_ARN",
+    "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI",
+];
+
+/// Environment variable approving profiles like `--yes-production`.
+const YES_PRODUCTION_ENV: &str = "AWS_UNLOCK_YES_PRODUCTION";
+
+/// Environment variable approving commands like `--yes-command`.
+const YES_COMMAND_ENV: &str = "AWS_UNLOCK_YES_COMMAND";
 
 #[derive(clap::Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
-    #[clap(long, default_value_t = false)]
+    #[clap(subcommand)]
+    subcommand: Option<SubCommand>,
+
+    #[clap(long, global = true, default_value_t = false)]
     silent: bool,
 
     #[clap(long, default_value_t = false)]
@@ -28,15 +74,146 @@ struct Args {
     #[clap(long, default_value_t = false)]
     init_if_missing: bool,
 
-    #[clap(short, long, default_value_t = 60)]
-    seconds: u64,
+    /// Approve unlocking the profile without asking, even if its tier requires confirmation.
+    /// Needed for such profiles when stdin is not a terminal, e.g. in CI. Can be given more than
+    /// once, or as a comma-separated list in AWS_UNLOCK_YES_PRODUCTION.
+    #[clap(long, global = true, value_name = "PROFILE", value_delimiter = ',')]
+    yes_production: Vec<String>,
+
+    /// Approve running the command with the profile without asking, even if a command rule
+    /// requires confirming it. `--yes-production` does not approve such commands. Can be given
+    /// more than once, or as a comma-separated list in AWS_UNLOCK_YES_COMMAND.
+    #[clap(long, global = true, value_name = "PROFILE", value_delimiter = ',')]
+    yes_command: Vec<String>,
+
+    /// Use targets already unlocked by another aws-unlock instead of failing. In exec mode the
+    /// command runs under their session, and they are left for that session to relock.
+    #[clap(long, default_value_t = false)]
+    join: bool,
+
+    /// How long to unlock the profiles for. Defaults to `duration` in the settings.
+    #[clap(short, long)]
+    seconds: Option<u64>,
+
+    /// What to do with cached CLI/SSO credentials of profiles being locked.
+    #[clap(long, global = true, value_enum, default_value_t = CachePolicy::Quarantine)]
+    cache_policy: CachePolicy,
 
+    /// What to do with a profile defined more than once in the same file. Original files are
+    /// backed up into ~/.aws-unlock/backups before duplicates are merged or dropped.
+    #[clap(long, global = true, value_enum, default_value_t = DuplicatePolicy::Error)]
+    on_duplicate: DuplicatePolicy,
+
+    /// How to keep credentials locked by `--lock-all`. Defaults to `backend` in the settings.
+    #[clap(long, value_enum)]
+    backend: Option<LockBackend>,
+
+    /// Output format of `--list`, `status`, `check` and `config show`. Defaults to `output` in
+    /// the settings.
+    #[clap(long, global = true, value_enum)]
+    output: Option<OutputFormat>,
+
+    /// When to color output. Defaults to `color` in the settings.
+    #[clap(long, global = true, value_enum)]
+    color: Option<ColorChoice>,
+
+    /// In exec mode, serve credentials through a local container credentials endpoint instead
+    /// of passing raw keys in the environment.
+    #[clap(long, default_value_t = false)]
+    serve_credentials: bool,
+
+    /// Also unlock the profiles whose names match the regular expression. Can be given more than
+    /// once.
+    #[clap(long = "regex", value_name = "REGEX", value_parser = Regex::new)]
+    regexes: Vec<Regex>,
+
+    /// Profiles to unlock. `@name` unlocks the profiles of a group, and aliases are expanded,
+    /// as defined in the settings. Names containing `*`, `?` or `[` are globs selecting the
+    /// matching profiles.
     target_profiles: Vec<String>,
 
     #[clap(last(true))]
     commands: Vec<String>,
 }
 
+#[derive(clap::Subcommand)]
+enum SubCommand {
+    /// Print credentials of a profile in the `credential_process` format. Fails unless the
+    /// profile is currently unlocked by aws-unlock.
+    CredentialProcess { profile: String },
+
+    /// Make a profile source its credentials from `aws-unlock credential-process`, moving its
+    /// static keys out of ~/.aws/credentials into aws-unlock's locked store.
+    Install { profile: String },
+
+    /// Unlock a profile and serve its credentials on a loopback endpoint in the container
+    /// credentials format, for tools that honor `AWS_CONTAINER_CREDENTIALS_FULL_URI`.
+    Serve {
+        profile: String,
+
+        /// How long to keep the profile unlocked, e.g. `30m`.
+        #[clap(long = "for", value_parser = humantime::parse_duration, default_value = "15m")]
+        duration: Duration,
+    },
+
+    /// Validate ~/.aws/config and ~/.aws/credentials without modifying them. Exits with 1 if
+    /// any problem is found.
+    Check,
+
+    /// Show the lock state of each profile. Half-locked profiles, with some lines commented out
+    /// and others not, are shown as inconsistent.
+    Status,
+
+    /// Lock half-locked profiles fully. Repairs all of them if no profile is given. With
+    /// `--on-duplicate`, duplicate sections are resolved as well.
+    Repair { profiles: Vec<String> },
+
+    /// Show or edit `# aws-unlock: key=value` annotations of a profile, e.g. `owner=infra`.
+    /// An empty value removes the annotation; `tier=<tier>` sets the tier. Shows the annotations
+    /// if none are given.
+    Annotate {
+        profile: String,
+        annotations: Vec<String>,
+    },
+
+    /// Manage the encrypted vault for locked credentials.
+    #[clap(subcommand)]
+    Vault(VaultCommand),
+
+    /// Inspect the settings of aws-unlock.
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+
+    /// Manage the TOTP secret asked for by tiers with `totp = true`.
+    #[clap(subcommand)]
+    Totp(TotpCommand),
+}
+
+#[derive(clap::Subcommand)]
+enum TotpCommand {
+    /// Generate a secret, show it as an otpauth URI and a QR code for an authenticator app, and
+    /// save it once a code from the app is verified.
+    Enroll {
+        /// Replace the secret already enrolled.
+        #[clap(long, default_value_t = false)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Print the effective settings, merged from the defaults, the user settings file and the
+    /// project settings file, along with where each value comes from.
+    Show,
+}
+
+#[derive(clap::Subcommand)]
+enum VaultCommand {
+    /// Move secrets of locked (commented out) profiles into the vault. Migrates all locked
+    /// profiles if none are given.
+    Migrate { profiles: Vec<String> },
+}
+
 macro_rules! may_print {
     ($silent:expr) => {
         if !$silent {
@@ -65,78 +242,610 @@ macro_rules! may_println {
 }
 
 #[tokio::main]
-async fn main() -> Result<ExitCode> {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            let code = e.downcast_ref::<Error>().map_or(1, Error::exit_code);
+            ExitCode::from(code)
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<ExitCode> {
+    let settings = Settings::load()?;
+    let mut confirmer = TerminalConfirmer::new(
+        approved_profiles(&settings, args.yes_production, YES_PRODUCTION_ENV)?,
+        approved_profiles(&settings, args.yes_command, YES_COMMAND_ENV)?,
+    );
+    let output = args.output.unwrap_or(settings.output);
+    let color = args.color.unwrap_or(settings.color);
+
+    match args.subcommand {
+        Some(SubCommand::CredentialProcess { profile }) => {
+            println!("{}", credential_process_output(&profile.into())?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Install { profile }) => {
+            install(&profile.into())?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Serve { profile, duration }) => {
+            let profile = profile.into();
+            settings.check_allowed(std::slice::from_ref(&profile))?;
+            serve(
+                args.silent,
+                profile,
+                duration,
+                args.cache_policy,
+                args.on_duplicate,
+                &mut confirmer,
+            )
+            .await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Check) => {
+            return run_check(args.silent, output);
+        }
+        Some(SubCommand::Status) => {
+            status(args.on_duplicate, output, color)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Repair { profiles }) => {
+            let profiles: Vec<_> = profiles.into_iter().map(Into::into).collect();
+            repair(args.silent, &profiles, args.cache_policy, args.on_duplicate)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Annotate {
+            profile,
+            annotations,
+        }) => {
+            annotate(profile.into(), &annotations, args.on_duplicate)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Config(ConfigCommand::Show)) => {
+            config_show(&settings, output)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Totp(TotpCommand::Enroll { force })) => {
+            totp_enroll(force, &mut confirmer)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(SubCommand::Vault(VaultCommand::Migrate { profiles })) => {
+            let profiles: Vec<_> = profiles.into_iter().map(Into::into).collect();
+            migrate_to_vault(args.silent, &profiles, args.on_duplicate)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
+    }
 
     if args.lock_all {
-        lock_all()?;
+        let backend = args.backend.unwrap_or(settings.backend);
+        lock_all(args.cache_policy, args.on_duplicate, backend)?;
         return Ok(ExitCode::SUCCESS);
     }
 
     if args.list {
-        list()?;
+        list(args.on_duplicate, output)?;
         return Ok(ExitCode::SUCCESS);
     }
 
-    if args.target_profiles.is_empty() {
-        Args::command().print_long_help()?;
-        bail!("no target profiles are specified.");
-    }
-
     let is_silent = args.silent;
     let init_if_missing = args.init_if_missing;
+    let cache_policy = args.cache_policy;
+    let on_duplicate = args.on_duplicate;
+
+    let mut targets = args.target_profiles;
+    if targets.is_empty() && args.regexes.is_empty() {
+        let Some((target, source)) = default_target(&settings) else {
+            Args::command().print_long_help()?;
+            bail!("no target profiles are specified.");
+        };
+        may_println!(
+            is_silent,
+            "No profile given; using '{target}' from {source}"
+        );
+        targets.push(target);
+    }
 
-    let target_profiles: Vec<_> = args.target_profiles.into_iter().map(Into::into).collect();
+    let profile_names: Vec<_> = AwsFile::open()?
+        .with_duplicate_policy(on_duplicate)
+        .parse()?
+        .into_iter()
+        .map(|profile| profile.name)
+        .collect();
+    settings.check_names(&profile_names)?;
+    let target_profiles = settings.expand_targets(&targets)?;
+    let (target_profiles, selected_by_pattern) =
+        select_profiles(is_silent, target_profiles, &args.regexes, on_duplicate)?;
+    settings.check_allowed(&target_profiles)?;
+    let mut checks = UnlockChecks {
+        selected_by_pattern,
+        ..Default::default()
+    };
     if init_if_missing {
-        init_missing_profiles(is_silent, &target_profiles)?;
+        init_missing_profiles(is_silent, &target_profiles, on_duplicate)?;
     }
-    let (locked_profiles, unlocked_profiles) = check_current_lock_status(&target_profiles)?;
-    if !unlocked_profiles.is_empty() {
-        let unlocked_profiles = unlocked_profiles
-            .iter()
-            .map(|s| format!("'{s}'"))
-            .format(", ");
-        bail!("profile {unlocked_profiles} is not locked");
+    let (locked_profiles, unlocked_profiles) =
+        check_current_lock_status(&target_profiles, on_duplicate)?;
+    if !unlocked_profiles.is_empty() && !args.join {
+        return Err(Error::NotLocked(unlocked_profiles).into());
+    }
+    for profile in &unlocked_profiles {
+        let Some(lease) = Lease::active(profile)? else {
+            return Err(Error::NotJoinable(vec![profile.clone()]).into());
+        };
+        let until = lease.expires_at().map_or_else(
+            || "until it exits".to_string(),
+            |expires_at| format!("until {}", format_rfc3339_seconds(expires_at)),
+        );
+        may_println!(
+            is_silent,
+            "joining the session of '{profile}' held by pid {} {until}",
+            lease.pid
+        );
     }
 
     if args.commands.is_empty() {
+        if locked_profiles.is_empty() {
+            may_println!(is_silent, "All target profiles are already unlocked.");
+            return Ok(ExitCode::SUCCESS);
+        }
+
         unlock_during_specified_duration(
             is_silent,
             &locked_profiles,
-            Duration::from_secs(args.seconds),
+            args.seconds.map_or(settings.duration, Duration::from_secs),
+            cache_policy,
+            on_duplicate,
+            &mut confirmer,
+            &mut checks,
         )
         .await?;
 
         Ok(ExitCode::SUCCESS)
     } else {
-        unlock_during_commands(is_silent, &locked_profiles, args.commands).await
+        unlock_during_commands(
+            is_silent,
+            &locked_profiles,
+            &unlocked_profiles,
+            args.commands,
+            cache_policy,
+            on_duplicate,
+            &mut confirmer,
+            args.serve_credentials,
+            &mut checks,
+        )
+        .await
+    }
+}
+
+fn list(on_duplicate: DuplicatePolicy, output: OutputFormat) -> Result<()> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
+    let profiles = aws_file.parse()?;
+
+    if output == OutputFormat::Json {
+        let profiles: Vec<_> = profiles
+            .iter()
+            .map(|profile| {
+                json!({
+                    "name": profile.name.to_string(),
+                    "annotations": annotations_json(profile),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&profiles)?);
+        return Ok(());
+    }
+
+    let width = profiles
+        .iter()
+        .map(|p| p.name.to_string().len())
+        .max()
+        .unwrap_or(0);
+    for profile in &profiles {
+        if profile.annotations.is_empty() {
+            println!("{}", profile.name);
+        } else {
+            println!(
+                "{:width$}  {}",
+                profile.name.to_string(),
+                format_annotations(profile)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn annotations_json(profile: &AwsProfile) -> Value {
+    profile
+        .annotations
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::from(value)))
+        .collect()
+}
+
+fn format_annotations(profile: &AwsProfile) -> String {
+    profile
+        .annotations
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .join(" ")
+}
+
+fn annotate(
+    profile_name: ProfileName,
+    annotations: &[String],
+    on_duplicate: DuplicatePolicy,
+) -> Result<()> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
+    let mut profiles = aws_file.parse()?;
+    let Some(profile) = profiles.iter_mut().find(|p| p.name == profile_name) else {
+        return Err(Error::UnknownProfiles(vec![profile_name]).into());
+    };
+
+    if annotations.is_empty() {
+        println!("tier={}", profile.tier);
+        for (key, value) in profile.annotations.iter() {
+            println!("{key}={value}");
+        }
+        return Ok(());
+    }
+
+    for annotation in annotations {
+        let Some((key, value)) = annotation.split_once('=') else {
+            bail!("invalid annotation '{annotation}': expected KEY=VALUE");
+        };
+        if key.eq_ignore_ascii_case("tier") {
+            profile.tier = match value.trim() {
+                "" => Tier::Dev,
+                tier => tier
+                    .parse()
+                    .map_err(|()| anyhow!("unknown tier '{tier}'"))?,
+            };
+        } else if let Err(reason) = profile.annotations.set(key, value) {
+            bail!("invalid annotation '{key}': {reason}");
+        }
+    }
+
+    aws_file.write(&profiles)?;
+    aws_file.flush()?;
+
+    Ok(())
+}
+
+fn run_check(is_silent: bool, output: OutputFormat) -> Result<ExitCode> {
+    let problems = check()?;
+    if output == OutputFormat::Json {
+        let problems: Vec<_> = problems
+            .iter()
+            .map(|problem| {
+                json!({
+                    "path": problem.path,
+                    "line": problem.span.map(|span| span.line),
+                    "column": problem.span.map(|span| span.column),
+                    "message": problem.kind.to_string(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&problems)?);
+        return Ok(if problems.is_empty() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    for problem in &problems {
+        println!("{problem}");
+    }
+
+    if problems.is_empty() {
+        may_println!(is_silent, "No problems found.");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        may_println!(is_silent, "{} problems found.", problems.len());
+        Ok(ExitCode::FAILURE)
     }
 }
 
-fn list() -> Result<()> {
-    let mut aws_file = AwsFile::open()?;
+fn status(on_duplicate: DuplicatePolicy, output: OutputFormat, color: ColorChoice) -> Result<()> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
     let profiles = aws_file.parse()?;
 
+    let width = profiles
+        .iter()
+        .map(|p| p.name.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let mut statuses = vec![];
     for profile in &profiles {
-        println!("{}", profile.name);
+        let lease = match profile.lock_state {
+            LockState::Unlocked => Lease::active(&profile.name)?,
+            LockState::Locked | LockState::Inconsistent => None,
+        };
+        let tier_source = match &profile.rule_match {
+            Some(rule_match) if rule_match.tier > profile.tier => Some(rule_match.reason.clone()),
+            _ if profile.tier != Tier::Dev => Some("marker".to_string()),
+            _ => None,
+        };
+
+        if output == OutputFormat::Json {
+            statuses.push(json!({
+                "name": profile.name.to_string(),
+                "state": profile.lock_state.to_string(),
+                "tier": profile.effective_tier().to_string(),
+                "tier_source": tier_source,
+                "annotations": annotations_json(profile),
+                "held_by": lease.as_ref().map(|lease| json!({
+                    "pid": lease.pid,
+                    "expires_at": lease.expires_at().map(|at| format_rfc3339_seconds(at).to_string()),
+                })),
+            }));
+            continue;
+        }
+
+        let mut notes = vec![];
+        match &profile.rule_match {
+            Some(rule_match) if rule_match.tier > profile.tier => notes.push(format!(
+                "{} by rule: {}",
+                rule_match.tier, rule_match.reason
+            )),
+            _ if profile.tier != Tier::Dev => notes.push(format!("{} by marker", profile.tier)),
+            _ => {}
+        }
+        if profile.lock_state == LockState::Inconsistent {
+            notes.push("run `aws-unlock repair`".to_string());
+        }
+        if let Some(lease) = &lease {
+            let until = lease.expires_at().map_or_else(
+                || "until it exits".to_string(),
+                |expires_at| format!("until {}", format_rfc3339_seconds(expires_at)),
+            );
+            notes.push(format!("held by pid {} {until}", lease.pid));
+        }
+
+        let notes = if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", notes.join(", "))
+        };
+        let state = if color.is_enabled() {
+            let code = match profile.lock_state {
+                LockState::Locked => 32,
+                LockState::Unlocked => 33,
+                LockState::Inconsistent => 31,
+            };
+            format!("\x1b[{code}m{}\x1b[0m", profile.lock_state)
+        } else {
+            profile.lock_state.to_string()
+        };
+        println!("{:width$}  {state}{notes}", profile.name.to_string());
+        for (key, value) in profile.annotations.iter() {
+            println!("{:width$}    {key}: {value}", "");
+        }
+    }
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+    }
+
+    Ok(())
+}
+
+fn config_show(settings: &Settings, output: OutputFormat) -> Result<()> {
+    let entries = settings.entries();
+    if output == OutputFormat::Json {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(key, value, source)| {
+                json!({
+                    "key": key,
+                    "value": value,
+                    "source": source,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    for file in &settings.files {
+        println!("# read {}", file.display());
+    }
+    let width = entries
+        .iter()
+        .map(|(key, value, _)| key.len() + value.len() + 3)
+        .max()
+        .unwrap_or(0);
+    for (key, value, source) in &entries {
+        let source = source.map_or_else(|| "default".into(), |path| path.to_string_lossy());
+        println!("{:width$}  # {source}", format!("{key} = {value}"));
     }
 
     Ok(())
 }
 
-fn lock_all() -> Result<()> {
-    let mut aws_file = AwsFile::open()?;
+fn repair(
+    is_silent: bool,
+    target_profiles: &[ProfileName],
+    cache_policy: CachePolicy,
+    on_duplicate: DuplicatePolicy,
+) -> Result<()> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
+    let mut profiles = aws_file.parse()?;
+
+    let unknown_profiles = target_profiles
+        .iter()
+        .filter(|name| profiles.iter().all(|p| p.name != **name))
+        .cloned()
+        .collect_vec();
+    if !unknown_profiles.is_empty() {
+        return Err(Error::UnknownProfiles(unknown_profiles).into());
+    }
+
+    let mut repaired_profiles = vec![];
+    for profile in &mut profiles {
+        let is_target = target_profiles.is_empty() || target_profiles.contains(&profile.name);
+        if is_target && profile.lock_state == LockState::Inconsistent {
+            profile.lock_state = LockState::Locked;
+            repaired_profiles.push(profile.name.clone());
+        }
+    }
+    if aws_file.has_resolved_duplicates() {
+        may_println!(is_silent, "Resolving duplicate sections...");
+    } else if repaired_profiles.is_empty() {
+        may_println!(is_silent, "No half-locked profiles.");
+        return Ok(());
+    }
+
+    if !repaired_profiles.is_empty() {
+        may_println!(
+            is_silent,
+            "Locking {} fully...",
+            repaired_profiles
+                .iter()
+                .map(|s| format!("'{s}'"))
+                .format(", ")
+        );
+    }
+    aws_file.write(&profiles)?;
+    aws_file.flush()?;
+
+    let repaired_profiles = profiles
+        .iter()
+        .filter(|p| repaired_profiles.contains(&p.name))
+        .collect_vec();
+    lock_caches(&profiles, &repaired_profiles, cache_policy)?;
+
+    Ok(())
+}
+
+fn lock_all(
+    cache_policy: CachePolicy,
+    on_duplicate: DuplicatePolicy,
+    backend: LockBackend,
+) -> Result<()> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
     let mut profiles = aws_file.parse()?;
     profiles
         .iter_mut()
-        .for_each(|profile| profile.is_locked = true);
+        .for_each(|profile| profile.lock_state = LockState::Locked);
+    if backend == LockBackend::Vault {
+        seal_profiles(&mut profiles.iter_mut().collect_vec())?;
+    }
+    aws_file.write(&profiles)?;
+
+    lock_caches(&profiles, &profiles.iter().collect_vec(), cache_policy)?;
+
+    Ok(())
+}
+
+fn totp_enroll(force: bool, confirmer: &mut dyn Confirmer) -> Result<()> {
+    if !force && Totp::load()?.is_some() {
+        bail!("a TOTP secret is already enrolled; pass --force to replace it");
+    }
+
+    let mut totp = Totp::generate();
+    let account = env::var("USER").unwrap_or_else(|_| "default".to_string());
+    let uri = totp.uri(&account);
+    println!("Scan the QR code or add the URI to your authenticator app:");
+    println!();
+    print!("{}", render_qr(&uri)?);
+    println!();
+    println!("{uri}");
+    println!();
+
+    let code = confirmer.ask("Enter the code shown by the app to finish: ")?;
+    if !totp.verify(&code) {
+        return Err(Error::Totp("invalid code; the secret is not enrolled".to_string()).into());
+    }
+    totp.save()?;
+    println!("TOTP secret enrolled.");
+
+    Ok(())
+}
+
+fn migrate_to_vault(
+    is_silent: bool,
+    target_profiles: &[ProfileName],
+    on_duplicate: DuplicatePolicy,
+) -> Result<()> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
+    let mut profiles = aws_file.parse()?;
+
+    let unknown_profiles = target_profiles
+        .iter()
+        .filter(|name| profiles.iter().all(|p| p.name != **name))
+        .cloned()
+        .collect_vec();
+    if !unknown_profiles.is_empty() {
+        return Err(Error::UnknownProfiles(unknown_profiles).into());
+    }
+
+    let unlocked_profiles = profiles
+        .iter()
+        .filter(|p| target_profiles.contains(&p.name) && !p.lock_state.is_locked())
+        .map(|p| p.name.clone())
+        .collect_vec();
+    if !unlocked_profiles.is_empty() {
+        return Err(Error::NotLocked(unlocked_profiles).into());
+    }
+
+    let mut migrated_profiles = profiles
+        .iter_mut()
+        .filter(|p| target_profiles.is_empty() || target_profiles.contains(&p.name))
+        .filter(|p| p.lock_state.is_locked() && p.data.cred.as_ref().is_some_and(|c| !is_sealed(c)))
+        .collect_vec();
+    if migrated_profiles.is_empty() {
+        may_println!(is_silent, "No profiles to migrate.");
+        return Ok(());
+    }
+
+    may_println!(
+        is_silent,
+        "Moving secrets of {} into the vault...",
+        migrated_profiles
+            .iter()
+            .map(|p| format!("'{}'", p.name))
+            .format(", ")
+    );
+    seal_profiles(&mut migrated_profiles)?;
     aws_file.write(&profiles)?;
+    aws_file.flush()?;
+
+    Ok(())
+}
+
+/// Moves secrets of the profiles into the vault. The vault is saved before the caller writes
+/// the placeholders, so the secrets are never only in memory.
+fn seal_profiles(profiles: &mut [&mut AwsProfile]) -> Result<()> {
+    if profiles
+        .iter()
+        .all(|p| p.data.cred.as_ref().is_none_or(is_sealed))
+    {
+        return Ok(());
+    }
+
+    let mut vault = Vault::open()?;
+    for profile in profiles {
+        if let Some(cred) = &mut profile.data.cred {
+            vault.seal(&profile.name, cred);
+        }
+    }
+    vault.save()?;
 
     Ok(())
 }
 
-fn init_missing_profiles(is_silent: bool, target_profiles: &[ProfileName]) -> Result<()> {
-    let mut aws_file = AwsFile::open()?;
+fn init_missing_profiles(
+    is_silent: bool,
+    target_profiles: &[ProfileName],
+    on_duplicate: DuplicatePolicy,
+) -> Result<()> {
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
     let mut profiles = aws_file.parse()?;
 
     let missing_profiles = target_profiles
@@ -158,14 +867,17 @@ fn init_missing_profiles(is_silent: bool, target_profiles: &[ProfileName]) -> Re
     );
 
     for profile_name in missing_profiles {
-        // Heuristic to determine if the profile is production
-        let is_production =
-            matches!(profile_name, ProfileName::Named(name) if name.ends_with("production"));
+        // Production profiles are detected by the production rules, without a marker
         let empty_profile = AwsProfile {
             name: profile_name.clone(),
-            data: Default::default(),
-            is_locked: true,
-            is_production,
+            data: AwsProfileData {
+                conf: Default::default(),
+                cred: Some(Default::default()),
+            },
+            lock_state: LockState::Locked,
+            tier: Tier::Dev,
+            rule_match: None,
+            annotations: Default::default(),
         };
 
         profiles.push(empty_profile);
@@ -177,13 +889,115 @@ fn init_missing_profiles(is_silent: bool, target_profiles: &[ProfileName]) -> Re
     Ok(())
 }
 
+/// Returns the profiles approved by a flag such as `--yes-production` and its environment
+/// variable `env_name`, with groups and aliases expanded.
+fn approved_profiles(
+    settings: &Settings,
+    mut approvals: Vec<String>,
+    env_name: &str,
+) -> Result<Vec<ProfileName>> {
+    if let Ok(value) = env::var(env_name) {
+        approvals.extend(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|profile| !profile.is_empty())
+                .map(str::to_string),
+        );
+    }
+
+    Ok(settings.expand_targets(&approvals)?)
+}
+
+/// Returns the target to use when none is given, along with where it comes from: `AWS_PROFILE`,
+/// or `default_profile` in the settings.
+fn default_target(settings: &Settings) -> Option<(String, String)> {
+    if let Some(profile) = env::var("AWS_PROFILE").ok().filter(|p| !p.is_empty()) {
+        return Some((profile, "AWS_PROFILE".to_string()));
+    }
+
+    let profile = settings.default_profile.clone()?;
+    let source = settings.source("default_profile").map_or_else(
+        || "the settings".to_string(),
+        |path| path.display().to_string(),
+    );
+    Some((profile, source))
+}
+
+/// Replaces globs in `targets` and adds profiles matching `regexes`, keeping other names as is.
+/// Selections are previewed, and ones including production profiles must be confirmed.
+fn select_profiles(
+    is_silent: bool,
+    targets: Vec<ProfileName>,
+    regexes: &[Regex],
+    on_duplicate: DuplicatePolicy,
+) -> Result<(Vec<ProfileName>, bool)> {
+    let is_glob = |name: &ProfileName| name.to_string().contains(['*', '?', '[']);
+    if regexes.is_empty() && !targets.iter().any(is_glob) {
+        return Ok((targets, false));
+    }
+
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
+    let profiles = aws_file.parse()?;
+
+    let mut selections = vec![];
+    for target in targets {
+        if !is_glob(&target) {
+            selections.push((None, vec![target]));
+            continue;
+        }
+
+        let pattern = Pattern::new(&target.to_string())?;
+        let matched = profiles
+            .iter()
+            .filter(|p| pattern.matches(&p.name.to_string()))
+            .map(|p| p.name.clone())
+            .collect_vec();
+        selections.push((Some(format!("'{target}'")), matched));
+    }
+    for regex in regexes {
+        let matched = profiles
+            .iter()
+            .filter(|p| regex.is_match(&p.name.to_string()))
+            .map(|p| p.name.clone())
+            .collect_vec();
+        selections.push((Some(format!("/{regex}/")), matched));
+    }
+
+    let mut selected = vec![];
+    let mut unmatched = vec![];
+    for (selector, matched) in selections {
+        let Some(selector) = selector else {
+            selected.extend(matched);
+            continue;
+        };
+        if matched.is_empty() {
+            unmatched.push(selector);
+            continue;
+        }
+
+        may_println!(
+            is_silent,
+            "{selector} matches {}",
+            matched.iter().map(|name| format!("'{name}'")).format(", ")
+        );
+        selected.extend(matched);
+    }
+    if !unmatched.is_empty() {
+        bail!("no profiles match {}", unmatched.join(", "));
+    }
+    let selected = selected.into_iter().unique().collect_vec();
+    Ok((selected, true))
+}
+
 fn check_current_lock_status(
     target_profiles: &[ProfileName],
+    on_duplicate: DuplicatePolicy,
 ) -> Result<(Vec<ProfileName>, Vec<ProfileName>)> {
     let mut locked_profiles = vec![];
     let mut unlocked_profiles = vec![];
 
-    let mut aws_file = AwsFile::open()?;
+    let mut aws_file = AwsFile::open()?.with_duplicate_policy(on_duplicate);
     let profiles = aws_file.parse()?;
 
     // check all target profiles exist
@@ -195,15 +1009,24 @@ fn check_current_lock_status(
     }
 
     if !unknown_profiles.is_empty() {
-        let unknown_profiles_fmt = unknown_profiles
-            .iter()
-            .map(|s| format!("'{s}'"))
-            .format(", ");
-        bail!("some target profiles not found: {unknown_profiles_fmt}",)
+        return Err(Error::UnknownProfiles(unknown_profiles).into());
+    }
+
+    let inconsistent_profiles = profiles
+        .iter()
+        .filter(|p| target_profiles.contains(&p.name))
+        .filter(|p| p.lock_state == LockState::Inconsistent)
+        .map(|p| p.name.clone())
+        .collect_vec();
+    if !inconsistent_profiles.is_empty() {
+        return Err(Error::Inconsistent(inconsistent_profiles).into());
     }
 
     for profile in profiles {
-        if profile.is_locked {
+        if !target_profiles.contains(&profile.name) {
+            continue;
+        }
+        if profile.lock_state.is_locked() {
             locked_profiles.push(profile.name)
         } else {
             unlocked_profiles.push(profile.name)
@@ -217,6 +1040,10 @@ async fn unlock_during_specified_duration(
     is_silent: bool,
     target_profiles: &[ProfileName],
     dur: Duration,
+    cache_policy: CachePolicy,
+    on_duplicate: DuplicatePolicy,
+    confirmer: &mut dyn Confirmer,
+    checks: &mut UnlockChecks,
 ) -> Result<()> {
     // prepare timer
     let (timer, canceller) = ObservableTimer::new()?;
@@ -228,7 +1055,16 @@ async fn unlock_during_specified_duration(
         }
     })?;
 
-    let _guard = AwsLockGuard::unlock(target_profiles, true, !is_silent)?;
+    let _guard = AwsLockGuard::unlock(
+        target_profiles,
+        true,
+        confirmer,
+        cache_policy,
+        on_duplicate,
+        Purpose::Until(SystemTime::now() + dur),
+        false,
+        checks,
+    )?;
 
     may_println!(
         is_silent,
@@ -240,6 +1076,91 @@ async fn unlock_during_specified_duration(
         dur.as_secs(),
     );
 
+    sleep_with_countdown(is_silent, timer, dur).await;
+
+    Ok(())
+}
+
+async fn serve(
+    is_silent: bool,
+    profile: ProfileName,
+    dur: Duration,
+    cache_policy: CachePolicy,
+    on_duplicate: DuplicatePolicy,
+    confirmer: &mut dyn Confirmer,
+) -> Result<()> {
+    // prepare timer
+    let (timer, canceller) = ObservableTimer::new()?;
+    let (exit_tx, exit_rx) = mpsc::channel();
+
+    ctrlc::set_handler(move || {
+        may_println!(is_silent, "Ctrl+C detected. Stopping server...");
+        if let Err(e) = canceller.cancel() {
+            may_println!(is_silent, "cancellation failed! reason: {}", e);
+        }
+        let _ = exit_tx.send(());
+    })?;
+
+    let target_profiles = [profile];
+    let expires_at = SystemTime::now() + dur;
+    let guard = AwsLockGuard::unlock(
+        &target_profiles,
+        true,
+        confirmer,
+        cache_policy,
+        on_duplicate,
+        Purpose::Until(expires_at),
+        true,
+        &mut UnlockChecks::default(),
+    )?;
+
+    let profile = guard
+        .profiles
+        .iter()
+        .find(|p| p.name == target_profiles[0])
+        .expect("internal error: failed to find target profile");
+    let server = CredentialServer::bind(credentials_of(profile)?, Some(expires_at)).await?;
+    // The SDKs prefer these to the endpoint, so ones already in the shell would bypass it
+    for name in SHADOWING_ENVVARS {
+        println!("unset {name}");
+    }
+    println!(
+        "export AWS_CONTAINER_CREDENTIALS_FULL_URI={}",
+        server.uri()?
+    );
+    println!(
+        "export AWS_CONTAINER_AUTHORIZATION_TOKEN={}",
+        server.token()
+    );
+    let server = spawn(server.serve());
+
+    may_println!(
+        is_silent,
+        "serving profile '{}' for {} seconds...",
+        target_profiles[0],
+        dur.as_secs(),
+    );
+
+    let expired = sleep_with_countdown(is_silent, timer, dur).await;
+    drop(guard);
+
+    if expired {
+        // Keep answering so that clients see a clear 403 rather than a refused connection
+        may_println!(
+            is_silent,
+            "profile '{}' locked again. Answering 403 until Ctrl+C...",
+            target_profiles[0]
+        );
+        spawn_blocking(move || exit_rx.recv()).await?.ok();
+    }
+    server.abort();
+
+    Ok(())
+}
+
+/// Sleeps for `dur` while printing the remaining time. Returns `false` if the timer was
+/// cancelled.
+async fn sleep_with_countdown(is_silent: bool, timer: ObservableTimer, dur: Duration) -> bool {
     let res = timer
         .sleep(dur, Duration::from_millis(1000), |remaining| {
             may_print!(
@@ -251,48 +1172,113 @@ async fn unlock_during_specified_duration(
         .await;
 
     match res {
-        Ok(_) => may_println!(is_silent),
-        Err(_) => may_println!(is_silent, "timer cancelled"),
+        Ok(_) => {
+            may_println!(is_silent);
+            true
+        }
+        Err(_) => {
+            may_println!(is_silent, "timer cancelled");
+            false
+        }
     }
-
-    Ok(())
 }
 
+/// Runs `commands` with `target_profiles` unlocked and `joined_profiles` used under the sessions
+/// of other aws-unlock processes.
+#[allow(clippy::too_many_arguments)]
 async fn unlock_during_commands(
     is_silent: bool,
     target_profiles: &[ProfileName],
+    joined_profiles: &[ProfileName],
     commands: Vec<String>,
+    cache_policy: CachePolicy,
+    on_duplicate: DuplicatePolicy,
+    confirmer: &mut dyn Confirmer,
+    serve_credentials: bool,
+    checks: &mut UnlockChecks,
 ) -> Result<ExitCode> {
-    let guard = AwsLockGuard::unlock(target_profiles, true, !is_silent)?;
+    let joined = if joined_profiles.is_empty() {
+        vec![]
+    } else {
+        join_sessions(
+            joined_profiles,
+            confirmer,
+            on_duplicate,
+            Purpose::Command(&commands),
+            checks,
+        )?
+    };
+
+    // Credentials are passed through the environment only when there is a single target
+    let single_target = match (target_profiles, joined_profiles) {
+        ([target], []) | ([], [target]) => Some(target),
+        _ => None,
+    };
+    let guard = if target_profiles.is_empty() {
+        None
+    } else {
+        Some(AwsLockGuard::unlock(
+            target_profiles,
+            true,
+            confirmer,
+            cache_policy,
+            on_duplicate,
+            Purpose::Command(&commands),
+            single_target.is_some(),
+            checks,
+        )?)
+    };
+    let profiles = guard.as_ref().map_or(&joined, |guard| &guard.profiles);
 
     let mut envvars = HashMap::new();
-    if guard.target_profiles.len() == 1 {
-        let profile = guard
-            .profiles
+    let mut removed_envvars: &[&str] = &[];
+    let mut server: Option<JoinHandle<aws_unlock::error::Result<()>>> = None;
+    if let Some(target) = single_target {
+        let profile = profiles
             .iter()
-            .find(|p| p.name == guard.target_profiles[0])
+            .find(|p| p.name == *target)
             .expect("internal error: failed to find target profile");
 
-        if let ProfileName::Named(name) = &profile.name {
-            envvars.insert("AWS_PROFILE", name.clone());
-        }
+        if serve_credentials {
+            let credential_server = CredentialServer::bind(credentials_of(profile)?, None).await?;
+            envvars.insert(
+                "AWS_CONTAINER_CREDENTIALS_FULL_URI",
+                credential_server.uri()?,
+            );
+            envvars.insert(
+                "AWS_CONTAINER_AUTHORIZATION_TOKEN",
+                credential_server.token().to_string(),
+            );
+            server = Some(spawn(credential_server.serve()));
 
-        envvars.insert(
-            "AWS_ACCESS_KEY_ID",
-            profile.data.cred.aws_access_key_id.clone(),
-        );
-        envvars.insert(
-            "AWS_SECRET_ACCESS_KEY",
-            profile.data.cred.aws_secret_access_key.clone(),
-        );
-        if let Some(token) = &profile.data.cred.aws_session_token {
-            envvars.insert("AWS_SESSION_TOKEN", token.clone());
+            // The SDKs prefer these to the endpoint, so inherited ones would bypass it
+            removed_envvars = &SHADOWING_ENVVARS;
+        } else {
+            if let ProfileName::Named(name) = &profile.name {
+                envvars.insert("AWS_PROFILE", name.clone());
+            }
+
+            // Profiles without credentials (SSO, assume-role, ...) are resolved by the SDK
+            // itself through AWS_PROFILE.
+            if let Some(cred) = &profile.data.cred {
+                envvars.insert("AWS_ACCESS_KEY_ID", cred.aws_access_key_id.clone());
+                envvars.insert(
+                    "AWS_SECRET_ACCESS_KEY",
+                    cred.aws_secret_access_key.expose().to_string(),
+                );
+                if let Some(token) = &cred.aws_session_token {
+                    envvars.insert("AWS_SESSION_TOKEN", token.expose().to_string());
+                }
+            }
         }
 
         // Set AWS_REGION from profile if available
-        if let Some(region) = &profile.data.conf.region {
-            envvars.insert("AWS_REGION", region.clone());
-        } else if let Some(region) = &profile.data.cred.region {
+        let cred_region = profile
+            .data
+            .cred
+            .as_ref()
+            .and_then(|cred| cred.region.as_ref());
+        if let Some(region) = profile.data.conf.region.as_ref().or(cred_region) {
             envvars.insert("AWS_REGION", region.clone());
         }
     }
@@ -306,11 +1292,15 @@ async fn unlock_during_commands(
         );
     })?;
 
-    let mut child = Command::new(commands[0].as_str())
-        .args(&commands[1..])
-        .envs(envvars)
-        .spawn()?;
+    let mut command = Command::new(commands[0].as_str());
+    for name in removed_envvars {
+        command.env_remove(name);
+    }
+    let mut child = command.args(&commands[1..]).envs(envvars).spawn()?;
     let status = child.wait().await?;
+    if let Some(server) = server {
+        server.abort();
+    }
 
     Ok(ExitCode::from(status.code().map(|c| c as u8).unwrap_or(1)))
 }
@@ -0,0 +1,119 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{line_lexer::Span, secret::redact_line};
+
+/// What went wrong while reading ~/.aws/config or ~/.aws/credentials.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseErrorKind {
+    #[error("unrecognized line: expected a header, an option, a comment or an empty line")]
+    UnrecognizedLine,
+
+    #[error("expected {expected}, found {found}")]
+    Unexpected { expected: String, found: String },
+
+    #[error("unexpected end of file: expected {expected}")]
+    UnexpectedEof { expected: String },
+
+    #[error("unknown header {header:?}: expected [default], [profile <name>], [sso-session <name>] or [services <name>]")]
+    UnknownHeader { header: String },
+
+    #[error("unknown tier {tier:?}: expected dev, staging, production or break-glass")]
+    UnknownTier { tier: String },
+
+    #[error("invalid annotation '{key}': {reason}")]
+    InvalidAnnotation { key: String, reason: String },
+
+    #[error("missing key '{key}' in '{profile}' credentials")]
+    MissingKey { profile: String, key: String },
+
+    #[error("duplicate section for profile '{profile}', first defined on line {first_line} (see --on-duplicate)")]
+    DuplicateSection { profile: String, first_line: usize },
+}
+
+/// A parse error pointing into the source file. Once the source is attached with
+/// [`ParseError::with_source`], it renders with the offending line, with secret values
+/// redacted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+
+    /// Location of the error. `None` at the end of file.
+    pub span: Option<Span>,
+
+    /// Boxed to keep `Result<_, ParseError>` small.
+    source: Option<Box<Source>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Source {
+    path: PathBuf,
+    snippet: Option<String>,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Option<Span>) -> Self {
+        Self {
+            kind,
+            span,
+            source: None,
+        }
+    }
+
+    /// Path of the file the error was found in, if attached.
+    pub fn path(&self) -> Option<&Path> {
+        self.source.as_ref().map(|source| source.path.as_path())
+    }
+
+    /// Attaches the file path and contents the error was found in.
+    pub fn with_source(mut self, path: &Path, contents: &str) -> Self {
+        let snippet = self
+            .span
+            .and_then(|span| contents.lines().nth(span.line - 1))
+            .map(redact_line);
+        self.source = Some(Box::new(Source {
+            path: path.to_path_buf(),
+            snippet,
+        }));
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(b, "{}", self.kind)?;
+
+        let path = self
+            .path()
+            .map_or_else(|| "<input>".into(), Path::to_string_lossy);
+        match self.span {
+            Some(span) => write!(b, "  --> {}:{}:{}", path, span.line, span.column)?,
+            None => write!(b, "  --> {} (end of file)", path)?,
+        }
+
+        if let (Some(span), Some(snippet)) = (
+            self.span,
+            self.source
+                .as_ref()
+                .and_then(|source| source.snippet.as_ref()),
+        ) {
+            let gutter = " ".repeat(span.line.to_string().len());
+            let marker_len = span.len.min(snippet.len().saturating_sub(span.column - 1));
+            writeln!(b)?;
+            writeln!(b, "{gutter} |")?;
+            writeln!(b, "{} | {}", span.line, snippet)?;
+            write!(
+                b,
+                "{gutter} | {}{}",
+                " ".repeat(span.column - 1),
+                "^".repeat(marker_len.max(1))
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Keys whose values are secrets and must never appear in output.
+pub const SECRET_KEYS: [&str; 3] = [
+    "aws_secret_access_key",
+    "aws_session_token",
+    "aws_security_token",
+];
+
+const REDACTED: &str = "<redacted>";
+
+/// A secret string such as an AWS secret access key. It is redacted in `Debug` and `Display`
+/// output and wiped from memory on drop; use [`Secret::expose`] to get the actual value.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        write!(b, "Secret({REDACTED})")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        write!(b, "{REDACTED}")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Returns true if the values of `key` are secrets.
+pub fn is_secret_key(key: &str) -> bool {
+    SECRET_KEYS.contains(&key.trim().to_ascii_lowercase().as_str())
+}
+
+/// Returns `value`, or a redaction marker if the values of `key` are secrets.
+pub fn redact_value<'a>(key: &str, value: &'a str) -> &'a str {
+    if is_secret_key(key) {
+        REDACTED
+    } else {
+        value
+    }
+}
+
+/// Redacts everything after the first secret key appearing in a raw line, so that a malformed
+/// line can be shown without leaking its value.
+pub fn redact_line(line: &str) -> String {
+    let lowercase = line.to_ascii_lowercase();
+    let secret_key_end = SECRET_KEYS
+        .iter()
+        .filter_map(|key| lowercase.find(key).map(|pos| pos + key.len()))
+        .min();
+
+    match secret_key_end {
+        Some(end) => format!("{} {REDACTED}", &line[..end]),
+        None => line.to_string(),
+    }
+}
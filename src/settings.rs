@@ -0,0 +1,1406 @@
+use glob::Pattern;
+use home::home_dir;
+use humantime::{format_duration, parse_duration};
+use itertools::Itertools;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    io::{stdout, ErrorKind, IsTerminal},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    annotation,
+    aws_profile::{AwsProfile, ProfileName},
+    error::{Error, Result},
+    tier::{Tier, TierPolicy},
+    vault::LockBackend,
+};
+
+/// Environment variable overriding the path of the user settings file.
+pub const SETTINGS_ENV: &str = "AWS_UNLOCK_CONFIG";
+
+/// File name of project settings, looked up from the current directory upwards.
+pub const PROJECT_SETTINGS_FILE: &str = ".aws-unlock.toml";
+
+/// Settings of aws-unlock: the defaults, overridden by the user settings file and then by the
+/// project settings file. See [`Settings::load`].
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// How long profiles are unlocked for unless `--seconds` is given.
+    pub duration: Duration,
+
+    pub output: OutputFormat,
+    pub color: ColorChoice,
+
+    /// Backups kept per file in ~/.aws-unlock/backups. 0 keeps all of them.
+    pub backup_retention: usize,
+
+    /// Backend used by `--lock-all` unless `--backend` is given.
+    pub backend: LockBackend,
+
+    tiers: BTreeMap<Tier, TierPolicy>,
+
+    /// How unlocking profiles of tiers that require confirmation is confirmed.
+    pub confirmation: Confirmation,
+
+    /// Rules detecting the tier of profiles regardless of their markers. A profile matched by
+    /// several rules gets the most sensitive tier.
+    pub production_rules: Vec<ProductionRule>,
+
+    /// Rules on the commands profiles can be unlocked for in exec mode. Rules add up across
+    /// settings files, so that a project can only restrict commands further.
+    pub command_rules: Vec<CommandRule>,
+
+    /// Target unlocked when no profile is given and `AWS_PROFILE` is not set. Can be a group or
+    /// an alias.
+    pub default_profile: Option<String>,
+
+    /// Named lists of targets, given as `@name`. Members can be profiles, aliases or `@groups`.
+    pub groups: BTreeMap<String, Vec<String>>,
+
+    /// Alternative names of targets, which can be profiles, other aliases or `@groups`.
+    pub aliases: BTreeMap<String, String>,
+
+    /// Profiles that can be unlocked in the current project, if its settings file restricts them.
+    pub profile_access: Option<ProfileAccess>,
+
+    /// Whether `production_rules` come from settings files rather than the default.
+    has_custom_rules: bool,
+
+    /// Settings files read, in the order they were applied.
+    pub files: Vec<PathBuf>,
+
+    /// File each value was read from, by its key in `config show`. Missing keys are defaults.
+    sources: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorChoice {
+    /// Color output on a terminal unless `NO_COLOR` is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// How to confirm unlocking profiles of tiers that require confirmation.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Confirmation {
+    pub mode: ConfirmMode,
+    pub reason: ReasonPolicy,
+
+    /// Time the prompt is shown before input is accepted. Anything typed ahead is discarded.
+    pub cooldown: Duration,
+}
+
+/// What to type in to confirm. Ordered from the least to the most demanding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfirmMode {
+    /// `y` once for all profiles.
+    #[default]
+    Yes,
+
+    /// The name of each profile.
+    Name,
+
+    /// The account ID of each profile, or its name if the account ID is unknown.
+    AccountId,
+}
+
+/// Whether to ask for the reason of unlocking, recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReasonPolicy {
+    #[default]
+    None,
+    Optional,
+    Required,
+}
+
+/// Conditions on a profile, all of which must hold for the rule to match. At least one
+/// condition is required.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProductionRule {
+    /// Glob on the profile name, e.g. `prd-*`.
+    #[serde(deserialize_with = "deserialize_pattern")]
+    pub name: Option<Pattern>,
+
+    /// Regular expression on the profile name, matched anywhere unless anchored.
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub name_regex: Option<Regex>,
+
+    /// AWS account IDs, taken from the `account_id` annotation, `sso_account_id` or `role_arn`.
+    pub account_ids: Vec<String>,
+
+    /// Regions of the profile.
+    pub regions: Vec<String>,
+
+    /// Glob on `role_arn`, e.g. `arn:aws:iam::*:role/Admin*`.
+    #[serde(deserialize_with = "deserialize_pattern")]
+    pub role_arn: Option<Pattern>,
+
+    /// Tier of the matched profiles. Defaults to production.
+    pub tier: Option<Tier>,
+}
+
+/// Commands a profile can be unlocked for, matched against the command line with the program
+/// reduced to its file name, e.g. `terraform plan -out=plan`. Patterns are globs on the whole
+/// command line, so `terraform plan*` matches `terraform plan` followed by any arguments.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandRule {
+    /// Glob on the names of the profiles the rule applies to.
+    #[serde(deserialize_with = "deserialize_pattern")]
+    pub profile: Option<Pattern>,
+
+    /// Tier of the profiles the rule applies to, along with the stricter tiers. Rules without
+    /// `profile` or `tier` apply to all profiles.
+    pub tier: Option<Tier>,
+
+    /// Commands allowed. If given, other commands are denied, and so is unlocking for a duration.
+    #[serde(deserialize_with = "deserialize_pattern_list")]
+    pub allow: Vec<Pattern>,
+
+    /// Commands denied, even if allowed. Unlocking for a duration is denied too.
+    #[serde(deserialize_with = "deserialize_pattern_list")]
+    pub deny: Vec<Pattern>,
+
+    /// Commands that must be confirmed, as must unlocking for a duration.
+    #[serde(deserialize_with = "deserialize_pattern_list")]
+    pub confirm: Vec<Pattern>,
+}
+
+/// Outcome of the command rules for unlocking a profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandVerdict {
+    Allowed,
+
+    /// Allowed once confirmed, because the command matches the pattern.
+    Confirm(Pattern),
+
+    /// Denied, for the reason given, e.g. `it matches 'terraform destroy*'`.
+    Denied(String),
+}
+
+/// Profiles that can be unlocked under the directory of a project settings file, given by its
+/// `[profiles]` table. Denied profiles take precedence over allowed ones.
+#[derive(Debug, Clone)]
+pub struct ProfileAccess {
+    /// Project settings file declaring the access.
+    pub path: PathBuf,
+
+    /// Globs on the names of the profiles allowed. `None` allows any profile not denied.
+    pub allow: Option<Vec<Pattern>>,
+
+    /// Globs on the names of the profiles denied.
+    pub deny: Vec<Pattern>,
+}
+
+/// A rule matched by a profile.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RuleMatch {
+    pub tier: Tier,
+
+    /// Why the rule matched, e.g. `name matches 'prd-*'`.
+    pub reason: String,
+}
+
+/// Contents of a settings file. Values not given keep those of the previous layer.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SettingsFile {
+    #[serde(deserialize_with = "deserialize_duration")]
+    duration: Option<Duration>,
+    output: Option<OutputFormat>,
+    color: Option<ColorChoice>,
+    backup_retention: Option<usize>,
+    backend: Option<LockBackend>,
+    tiers: BTreeMap<Tier, TierPolicyFile>,
+    confirmation: ConfirmationFile,
+    production_rules: Option<Vec<ProductionRule>>,
+    command_rules: Vec<CommandRule>,
+    profiles: Option<ProfilesFile>,
+    default_profile: Option<String>,
+    groups: BTreeMap<String, Vec<String>>,
+    aliases: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProfilesFile {
+    #[serde(deserialize_with = "deserialize_patterns")]
+    allow: Option<Vec<Pattern>>,
+    #[serde(deserialize_with = "deserialize_patterns")]
+    deny: Option<Vec<Pattern>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfirmationFile {
+    mode: Option<ConfirmMode>,
+    reason: Option<ReasonPolicy>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    cooldown: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TierPolicyFile {
+    confirm: Option<bool>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    max_duration: Option<Duration>,
+    commands: Option<Vec<String>>,
+    totp: Option<bool>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(60),
+            output: OutputFormat::default(),
+            color: ColorChoice::default(),
+            backup_retention: 20,
+            backend: LockBackend::default(),
+            tiers: Tier::ALL
+                .into_iter()
+                .map(|tier| (tier, tier.default_policy()))
+                .collect(),
+            confirmation: Confirmation::default(),
+            // aws-unlock used to treat profiles named `*production` as production
+            production_rules: vec![ProductionRule {
+                name: Some(Pattern::new("*production").expect("valid pattern")),
+                ..Default::default()
+            }],
+            command_rules: vec![],
+            default_profile: None,
+            groups: BTreeMap::new(),
+            aliases: BTreeMap::new(),
+            profile_access: None,
+            has_custom_rules: false,
+            files: vec![],
+            sources: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the user settings file ([`user_settings_path`]) and the project settings file
+    /// ([`project_settings_path`]) on top of the defaults. Project settings can only tighten
+    /// tier policies and add production rules, and cannot set values affecting the user's files
+    /// or targets, so that a cloned repository cannot relax them.
+    pub fn load() -> Result<Self> {
+        let mut settings = Settings::default();
+
+        let user_path = user_settings_path();
+        let is_explicit = env::var_os(SETTINGS_ENV).is_some_and(|path| !path.is_empty());
+        if let Some(file) = read_settings_file(&user_path, is_explicit)? {
+            settings.apply(file, &user_path, false)?;
+        }
+
+        if let Some(project_path) = project_settings_path() {
+            if let Some(file) = read_settings_file(&project_path, true)? {
+                settings.apply(file, &project_path, true)?;
+            }
+        }
+
+        Ok(settings)
+    }
+
+    pub fn policy(&self, tier: Tier) -> TierPolicy {
+        self.tiers
+            .get(&tier)
+            .cloned()
+            .unwrap_or_else(|| tier.default_policy())
+    }
+
+    /// Returns the most sensitive tier the rules assign to the profile, if any matches.
+    pub fn match_rules(&self, profile: &AwsProfile) -> Option<RuleMatch> {
+        self.production_rules
+            .iter()
+            .filter_map(|rule| rule.matches(profile))
+            .max_by_key(|rule_match| rule_match.tier)
+    }
+
+    /// Checks `command` against the command rules of the profile. `None` means unlocking for a
+    /// duration, which allows any command.
+    pub fn check_command(&self, profile: &AwsProfile, command: Option<&str>) -> CommandVerdict {
+        let name = profile.name.to_string();
+        let tier = profile.effective_tier();
+        let rules: Vec<_> = self
+            .command_rules
+            .iter()
+            .filter(|rule| rule.applies_to(&name, tier))
+            .collect();
+
+        let Some(command) = command else {
+            if rules
+                .iter()
+                .any(|rule| !rule.allow.is_empty() || !rule.deny.is_empty())
+            {
+                return CommandVerdict::Denied(
+                    "its command rules only allow unlocking for a command".to_string(),
+                );
+            }
+            return rules
+                .iter()
+                .find_map(|rule| rule.confirm.first())
+                .map_or(CommandVerdict::Allowed, |pattern| {
+                    CommandVerdict::Confirm(pattern.clone())
+                });
+        };
+
+        let matching = |patterns: &[Pattern]| {
+            patterns
+                .iter()
+                .find(|pattern| pattern.matches(command))
+                .cloned()
+        };
+        if let Some(pattern) = rules.iter().find_map(|rule| matching(&rule.deny)) {
+            return CommandVerdict::Denied(format!("it matches '{pattern}'"));
+        }
+        if let Some(rule) = rules
+            .iter()
+            .find(|rule| !rule.allow.is_empty() && matching(&rule.allow).is_none())
+        {
+            let allow = rule
+                .allow
+                .iter()
+                .map(|pattern| format!("'{pattern}'"))
+                .join(", ");
+            return CommandVerdict::Denied(format!("it matches none of {allow}"));
+        }
+
+        rules
+            .iter()
+            .find_map(|rule| matching(&rule.confirm))
+            .map_or(CommandVerdict::Allowed, CommandVerdict::Confirm)
+    }
+
+    /// Effective values as `(key, value, source)` for `config show`. The source is `None` for
+    /// defaults.
+    pub fn entries(&self) -> Vec<(String, String, Option<&Path>)> {
+        let mut entries = vec![
+            (
+                "duration".to_string(),
+                quote(format_duration(self.duration)),
+            ),
+            ("output".to_string(), quote(self.output)),
+            ("color".to_string(), quote(self.color)),
+            (
+                "backup_retention".to_string(),
+                self.backup_retention.to_string(),
+            ),
+            ("backend".to_string(), quote(backend_name(self.backend))),
+            (
+                "default_profile".to_string(),
+                self.default_profile
+                    .as_ref()
+                    .map_or_else(|| "none".to_string(), quote),
+            ),
+        ];
+
+        for (tier, policy) in &self.tiers {
+            entries.push((format!("tiers.{tier}.confirm"), policy.confirm.to_string()));
+            entries.push((
+                format!("tiers.{tier}.max_duration"),
+                policy
+                    .max_duration
+                    .map_or_else(|| "none".to_string(), |max| quote(format_duration(max))),
+            ));
+            entries.push((
+                format!("tiers.{tier}.commands"),
+                policy
+                    .commands
+                    .as_ref()
+                    .map_or_else(|| "any".to_string(), |commands| quote_list(commands)),
+            ));
+            entries.push((format!("tiers.{tier}.totp"), policy.totp.to_string()));
+        }
+
+        let confirmation = &self.confirmation;
+        entries.push(("confirmation.mode".to_string(), quote(confirmation.mode)));
+        entries.push((
+            "confirmation.reason".to_string(),
+            quote(confirmation.reason),
+        ));
+        entries.push((
+            "confirmation.cooldown".to_string(),
+            quote(format_duration(confirmation.cooldown)),
+        ));
+
+        for (index, rule) in self.production_rules.iter().enumerate() {
+            entries.push((format!("production_rules[{index}]"), rule.to_string()));
+        }
+
+        for (index, rule) in self.command_rules.iter().enumerate() {
+            entries.push((format!("command_rules[{index}]"), rule.to_string()));
+        }
+        for (name, members) in &self.groups {
+            entries.push((format!("groups.{name}"), quote_list(members)));
+        }
+        for (name, target) in &self.aliases {
+            entries.push((format!("aliases.{name}"), quote(target)));
+        }
+
+        if let Some(access) = &self.profile_access {
+            if let Some(allow) = &access.allow {
+                entries.push(("profiles.allow".to_string(), quote_patterns(allow)));
+            }
+            entries.push(("profiles.deny".to_string(), quote_patterns(&access.deny)));
+        }
+
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                let source = self.source(&key);
+                (key, value, source)
+            })
+            .collect()
+    }
+
+    /// Expands `@groups` and aliases in `targets` into profile names, recursively. Each profile
+    /// appears once, in the order it is first reached. Fails on unknown groups and on groups or
+    /// aliases that refer to themselves.
+    pub fn expand_targets(&self, targets: &[String]) -> Result<Vec<ProfileName>> {
+        let mut expanded = vec![];
+        for target in targets {
+            self.expand_target(target, &mut vec![], &mut expanded)?;
+        }
+
+        Ok(expanded)
+    }
+
+    fn expand_target(
+        &self,
+        target: &str,
+        chain: &mut Vec<String>,
+        expanded: &mut Vec<ProfileName>,
+    ) -> Result<()> {
+        let (key, members) = if let Some(group) = target.strip_prefix('@') {
+            let members = self
+                .groups
+                .get(group)
+                .ok_or_else(|| Error::UnknownProfiles(vec![target.into()]))?;
+            (format!("groups.{group}"), members.as_slice())
+        } else if let Some(alias) = self.aliases.get(target) {
+            (format!("aliases.{target}"), std::slice::from_ref(alias))
+        } else {
+            let profile = ProfileName::from(target);
+            if !expanded.contains(&profile) {
+                expanded.push(profile);
+            }
+            return Ok(());
+        };
+
+        if let Some(start) = chain.iter().position(|t| t == target) {
+            let cycle = chain[start..]
+                .iter()
+                .chain([&target.to_string()])
+                .join(" -> ");
+            return Err(Error::Settings {
+                path: self.source(&key).map(Path::to_path_buf).unwrap_or_default(),
+                message: format!("{key} refers to itself: {cycle}"),
+            });
+        }
+
+        chain.push(target.to_string());
+        for member in members {
+            self.expand_target(member, chain, expanded)?;
+        }
+        chain.pop();
+
+        Ok(())
+    }
+
+    /// Fails if a group or an alias has the name of one of the profiles, which it would hide.
+    pub fn check_names(&self, profiles: &[ProfileName]) -> Result<()> {
+        let groups = self
+            .groups
+            .keys()
+            .map(|name| ("groups", format!("@{name}")));
+        let aliases = self.aliases.keys().map(|name| ("aliases", name.clone()));
+        for (table, name) in groups.chain(aliases) {
+            if profiles.contains(&ProfileName::from(name.as_str())) {
+                let key = format!("{table}.{}", name.trim_start_matches('@'));
+                return Err(Error::Settings {
+                    path: self.source(&key).map(Path::to_path_buf).unwrap_or_default(),
+                    message: format!("{key} has the name of the profile '{name}'"),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the project settings file does not allow unlocking some of the profiles here.
+    pub fn check_allowed(&self, profiles: &[ProfileName]) -> Result<()> {
+        let Some(access) = &self.profile_access else {
+            return Ok(());
+        };
+
+        let denied: Vec<_> = profiles
+            .iter()
+            .filter(|profile| !access.is_allowed(&profile.to_string()))
+            .cloned()
+            .collect();
+        if denied.is_empty() {
+            return Ok(());
+        }
+
+        Err(Error::NotAllowedHere {
+            profiles: denied,
+            path: access.path.clone(),
+        })
+    }
+
+    /// File the value of `key` was read from, or `None` for defaults.
+    pub fn source(&self, key: &str) -> Option<&Path> {
+        self.sources.get(key).map(PathBuf::as_path)
+    }
+
+    fn apply(&mut self, file: SettingsFile, path: &Path, is_project: bool) -> Result<()> {
+        if is_project {
+            // Defaults such as `duration` can be set per project, as tier policies still bound them
+            let user_only_keys = [
+                ("[groups]", !file.groups.is_empty()),
+                ("[aliases]", !file.aliases.is_empty()),
+            ];
+            if let Some((key, _)) = user_only_keys.iter().find(|(_, is_set)| *is_set) {
+                return Err(Error::Settings {
+                    path: path.to_path_buf(),
+                    message: format!("{key} is only allowed in the user settings file"),
+                });
+            }
+        }
+
+        let mut set_source = |key: &str| {
+            self.sources.insert(key.to_string(), path.to_path_buf());
+        };
+        if let Some(duration) = file.duration {
+            self.duration = duration;
+            set_source("duration");
+        }
+        if let Some(output) = file.output {
+            self.output = output;
+            set_source("output");
+        }
+        if let Some(color) = file.color {
+            self.color = color;
+            set_source("color");
+        }
+        if let Some(backup_retention) = file.backup_retention {
+            self.backup_retention = backup_retention;
+            set_source("backup_retention");
+        }
+        if let Some(backend) = file.backend {
+            self.backend = backend;
+            set_source("backend");
+        }
+        if let Some(default_profile) = file.default_profile {
+            self.default_profile = Some(default_profile);
+            set_source("default_profile");
+        }
+
+        for (tier, policy_file) in file.tiers {
+            let current = self.policy(tier);
+            let mut policy = current.clone();
+            if let Some(confirm) = policy_file.confirm {
+                policy.confirm = confirm;
+            }
+            if let Some(max_duration) = policy_file.max_duration {
+                policy.max_duration = Some(max_duration);
+            }
+            if let Some(commands) = policy_file.commands {
+                policy.commands = Some(commands);
+            }
+            if let Some(totp) = policy_file.totp {
+                policy.totp = totp;
+            }
+            if is_project {
+                let mut tightened = current.clone();
+                tightened.tighten(policy);
+                policy = tightened;
+            }
+
+            for (key, is_changed) in [
+                ("confirm", policy.confirm != current.confirm),
+                ("max_duration", policy.max_duration != current.max_duration),
+                ("commands", policy.commands != current.commands),
+                ("totp", policy.totp != current.totp),
+            ] {
+                if is_changed {
+                    let key = format!("tiers.{tier}.{key}");
+                    self.sources.insert(key, path.to_path_buf());
+                }
+            }
+            self.tiers.insert(tier, policy);
+        }
+
+        // Like tier policies, project settings can only make confirmation stricter
+        let confirmation = &mut self.confirmation;
+        let file_confirmation = file.confirmation;
+        let mut changed = vec![];
+        if let Some(mode) = file_confirmation.mode {
+            if !is_project || mode > confirmation.mode {
+                confirmation.mode = mode;
+                changed.push("confirmation.mode");
+            }
+        }
+        if let Some(reason) = file_confirmation.reason {
+            if !is_project || reason > confirmation.reason {
+                confirmation.reason = reason;
+                changed.push("confirmation.reason");
+            }
+        }
+        if let Some(cooldown) = file_confirmation.cooldown {
+            if !is_project || cooldown > confirmation.cooldown {
+                confirmation.cooldown = cooldown;
+                changed.push("confirmation.cooldown");
+            }
+        }
+        for key in changed {
+            self.sources.insert(key.to_string(), path.to_path_buf());
+        }
+
+        if let Some(rules) = file.production_rules {
+            if let Some(index) = rules.iter().position(ProductionRule::is_empty) {
+                return Err(Error::Settings {
+                    path: path.to_path_buf(),
+                    message: format!("production_rules[{index}] has no conditions"),
+                });
+            }
+
+            // Rules in the user settings file replace the default rule. Rules in project settings
+            // files are added, so that they cannot drop the default rule.
+            if !self.has_custom_rules && !is_project {
+                self.production_rules.clear();
+                self.has_custom_rules = true;
+            }
+            for rule in rules {
+                let key = format!("production_rules[{}]", self.production_rules.len());
+                self.sources.insert(key, path.to_path_buf());
+                self.production_rules.push(rule);
+            }
+        }
+
+        if let Some(index) = file.command_rules.iter().position(CommandRule::is_empty) {
+            return Err(Error::Settings {
+                path: path.to_path_buf(),
+                message: format!("command_rules[{index}] has no patterns"),
+            });
+        }
+        for rule in file.command_rules {
+            let key = format!("command_rules[{}]", self.command_rules.len());
+            self.sources.insert(key, path.to_path_buf());
+            self.command_rules.push(rule);
+        }
+
+        for (name, members) in file.groups {
+            let name = name.trim_start_matches('@').to_string();
+            self.sources
+                .insert(format!("groups.{name}"), path.to_path_buf());
+            self.groups.insert(name, members);
+        }
+        for (name, target) in file.aliases {
+            self.sources
+                .insert(format!("aliases.{name}"), path.to_path_buf());
+            self.aliases.insert(name, target);
+        }
+
+        if let Some(profiles) = file.profiles {
+            if !is_project {
+                return Err(Error::Settings {
+                    path: path.to_path_buf(),
+                    message: format!(
+                        "[profiles] is only allowed in a project settings file ({PROJECT_SETTINGS_FILE})"
+                    ),
+                });
+            }
+
+            for key in ["profiles.allow", "profiles.deny"] {
+                self.sources.insert(key.to_string(), path.to_path_buf());
+            }
+            self.profile_access = Some(ProfileAccess {
+                path: path.to_path_buf(),
+                allow: profiles.allow,
+                deny: profiles.deny.unwrap_or_default(),
+            });
+        }
+
+        self.files.push(path.to_path_buf());
+
+        Ok(())
+    }
+}
+
+impl ColorChoice {
+    /// Whether to color output written to stdout.
+    pub fn is_enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => env::var_os("NO_COLOR").is_none() && stdout().is_terminal(),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputFormat::Text => write!(b, "text"),
+            OutputFormat::Json => write!(b, "json"),
+        }
+    }
+}
+
+impl fmt::Display for ColorChoice {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColorChoice::Auto => write!(b, "auto"),
+            ColorChoice::Always => write!(b, "always"),
+            ColorChoice::Never => write!(b, "never"),
+        }
+    }
+}
+
+impl fmt::Display for ConfirmMode {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfirmMode::Yes => write!(b, "yes"),
+            ConfirmMode::Name => write!(b, "name"),
+            ConfirmMode::AccountId => write!(b, "account-id"),
+        }
+    }
+}
+
+impl fmt::Display for ReasonPolicy {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReasonPolicy::None => write!(b, "none"),
+            ReasonPolicy::Optional => write!(b, "optional"),
+            ReasonPolicy::Required => write!(b, "required"),
+        }
+    }
+}
+
+impl fmt::Display for ProductionRule {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        let mut conditions = vec![];
+        if let Some(pattern) = &self.name {
+            conditions.push(format!("name = {}", quote(pattern)));
+        }
+        if let Some(regex) = &self.name_regex {
+            conditions.push(format!("name_regex = {}", quote(regex)));
+        }
+        if !self.account_ids.is_empty() {
+            conditions.push(format!("account_ids = {}", quote_list(&self.account_ids)));
+        }
+        if !self.regions.is_empty() {
+            conditions.push(format!("regions = {}", quote_list(&self.regions)));
+        }
+        if let Some(pattern) = &self.role_arn {
+            conditions.push(format!("role_arn = {}", quote(pattern)));
+        }
+        if let Some(tier) = self.tier {
+            conditions.push(format!("tier = {}", quote(tier)));
+        }
+
+        write!(b, "{{ {} }}", conditions.join(", "))
+    }
+}
+
+impl fmt::Display for CommandRule {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        let mut fields = vec![];
+        if let Some(pattern) = &self.profile {
+            fields.push(format!("profile = {}", quote(pattern)));
+        }
+        if let Some(tier) = self.tier {
+            fields.push(format!("tier = {}", quote(tier)));
+        }
+        for (key, patterns) in [
+            ("allow", &self.allow),
+            ("deny", &self.deny),
+            ("confirm", &self.confirm),
+        ] {
+            if !patterns.is_empty() {
+                fields.push(format!("{key} = {}", quote_patterns(patterns)));
+            }
+        }
+
+        write!(b, "{{ {} }}", fields.join(", "))
+    }
+}
+
+impl CommandRule {
+    fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty() && self.confirm.is_empty()
+    }
+
+    fn applies_to(&self, name: &str, tier: Tier) -> bool {
+        self.profile
+            .as_ref()
+            .is_none_or(|pattern| pattern.matches(name))
+            && self.tier.is_none_or(|rule_tier| tier >= rule_tier)
+    }
+}
+
+impl ProfileAccess {
+    /// Directory the access applies to, i.e. the one containing the settings file.
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(&self.path)
+    }
+
+    pub fn is_allowed(&self, name: &str) -> bool {
+        let is_denied = self.deny.iter().any(|pattern| pattern.matches(name));
+        let is_allowed = self
+            .allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|pattern| pattern.matches(name)));
+
+        is_allowed && !is_denied
+    }
+}
+
+impl ProductionRule {
+    fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.name_regex.is_none()
+            && self.account_ids.is_empty()
+            && self.regions.is_empty()
+            && self.role_arn.is_none()
+    }
+
+    fn matches(&self, profile: &AwsProfile) -> Option<RuleMatch> {
+        let name = profile.name.to_string();
+        let conf = &profile.data.conf;
+        let mut reasons = vec![];
+
+        if let Some(pattern) = &self.name {
+            if !pattern.matches(&name) {
+                return None;
+            }
+            reasons.push(format!("name matches '{pattern}'"));
+        }
+
+        if let Some(regex) = &self.name_regex {
+            if !regex.is_match(&name) {
+                return None;
+            }
+            reasons.push(format!("name matches /{regex}/"));
+        }
+
+        if !self.account_ids.is_empty() {
+            let account_id = account_id(profile)
+                .filter(|account_id| self.account_ids.iter().any(|id| id == account_id))?;
+            reasons.push(format!("account {account_id}"));
+        }
+
+        if !self.regions.is_empty() {
+            let cred_region = profile.data.cred.as_ref().and_then(|c| c.region.as_ref());
+            let region = conf
+                .region
+                .as_ref()
+                .or(cred_region)
+                .filter(|region| self.regions.contains(region))?;
+            reasons.push(format!("region {region}"));
+        }
+
+        if let Some(pattern) = &self.role_arn {
+            let role_arn = conf
+                .role_arn
+                .as_ref()
+                .filter(|role_arn| pattern.matches(role_arn))?;
+            reasons.push(format!("role {role_arn}"));
+        }
+
+        Some(RuleMatch {
+            tier: self.tier.unwrap_or(Tier::Production),
+            reason: reasons.join(" and "),
+        })
+    }
+}
+
+/// Account ID of the profile, from the `account_id` annotation, `sso_account_id` or the account
+/// field of `role_arn`.
+pub fn account_id(profile: &AwsProfile) -> Option<&str> {
+    let conf = &profile.data.conf;
+    profile
+        .annotations
+        .get(annotation::ACCOUNT_ID)
+        .or(conf.sso_account_id.as_deref())
+        .or_else(|| conf.role_arn.as_deref()?.split(':').nth(4))
+        .filter(|account_id| !account_id.is_empty())
+}
+
+/// Returns the path of the user settings file: `$AWS_UNLOCK_CONFIG` if set, otherwise
+/// `aws-unlock/config.toml` under `$XDG_CONFIG_HOME` or `~/.config`.
+pub fn user_settings_path() -> PathBuf {
+    if let Some(path) = env::var_os(SETTINGS_ENV).filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+
+    // Relative paths in XDG_CONFIG_HOME are invalid and ignored per the specification
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| {
+            let home_dir = home_dir().expect("failed to locate home directory");
+            home_dir.join(".config")
+        });
+
+    config_home.join("aws-unlock").join("config.toml")
+}
+
+/// Returns the nearest `.aws-unlock.toml` in the current directory or its ancestors.
+pub fn project_settings_path() -> Option<PathBuf> {
+    let current_dir = env::current_dir().ok()?;
+    current_dir
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_SETTINGS_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Reads a settings file. A missing file is `None` unless `is_required`.
+fn read_settings_file(path: &Path, is_required: bool) -> Result<Option<SettingsFile>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound && !is_required => return Ok(None),
+        Err(e) => {
+            return Err(Error::Settings {
+                path: path.to_path_buf(),
+                message: e.to_string(),
+            })
+        }
+    };
+
+    toml::from_str(&contents)
+        .map(Some)
+        .map_err(|e| Error::Settings {
+            path: path.to_path_buf(),
+            message: e.to_string().trim_end().to_string(),
+        })
+}
+
+fn quote(value: impl fmt::Display) -> String {
+    format!("{:?}", value.to_string())
+}
+
+fn quote_list(values: &[String]) -> String {
+    let values: Vec<_> = values.iter().map(quote).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn quote_patterns(patterns: &[Pattern]) -> String {
+    let patterns: Vec<_> = patterns.iter().map(quote).collect();
+    format!("[{}]", patterns.join(", "))
+}
+
+fn backend_name(backend: LockBackend) -> &'static str {
+    match backend {
+        LockBackend::Comment => "comment",
+        LockBackend::Vault => "vault",
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+    parse_duration(&duration)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_pattern<'de, D>(deserializer: D) -> Result<Option<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Pattern::new(&pattern)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_patterns<'de, D>(deserializer: D) -> Result<Option<Vec<Pattern>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_pattern_list<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_patterns(deserializer).map(Option::unwrap_or_default)
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let regex = String::deserialize(deserializer)?;
+    Regex::new(&regex)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+impl Settings {
+    /// Settings read from a user settings file holding `text`.
+    pub fn from_user_file(text: &str) -> Self {
+        let mut settings = Settings::default();
+        let file = toml::from_str(text).unwrap();
+        settings
+            .apply(file, Path::new("config.toml"), false)
+            .unwrap();
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{aws_profile::AwsProfileData, line_parser::LockState};
+
+    fn apply_project(settings: &mut Settings, project: &str) -> Result<()> {
+        let file = toml::from_str(project).unwrap();
+        settings.apply(file, Path::new(PROJECT_SETTINGS_FILE), true)
+    }
+
+    fn profile(name: &str, tier: Tier) -> AwsProfile {
+        AwsProfile {
+            name: name.into(),
+            tier,
+            rule_match: None,
+            annotations: Default::default(),
+            lock_state: LockState::Locked,
+            data: AwsProfileData::default(),
+        }
+    }
+
+    #[test]
+    fn denied_commands_take_precedence_over_allowed_ones() {
+        let settings = Settings::from_user_file(
+            r#"
+            [[command_rules]]
+            allow = ["terraform *"]
+            deny = ["terraform destroy*"]
+            "#,
+        );
+        let dev = profile("dev", Tier::Dev);
+
+        let check = |command| settings.check_command(&dev, Some(command));
+        assert_eq!(check("terraform plan"), CommandVerdict::Allowed);
+        assert_eq!(
+            check("terraform destroy -auto-approve"),
+            CommandVerdict::Denied("it matches 'terraform destroy*'".to_string())
+        );
+        assert_eq!(
+            check("make deploy"),
+            CommandVerdict::Denied("it matches none of 'terraform *'".to_string())
+        );
+    }
+
+    #[test]
+    fn rules_apply_by_profile_glob_and_to_stricter_tiers() {
+        let settings = Settings::from_user_file(
+            r#"
+            [[command_rules]]
+            profile = "prd-*"
+            confirm = ["terraform apply*"]
+
+            [[command_rules]]
+            tier = "production"
+            deny = ["aws s3 rm*"]
+            "#,
+        );
+        let command = Some("terraform apply -auto-approve");
+        let confirm = CommandVerdict::Confirm(Pattern::new("terraform apply*").unwrap());
+        assert_eq!(
+            settings.check_command(&profile("prd-billing", Tier::Dev), command),
+            confirm
+        );
+        assert_eq!(
+            settings.check_command(&profile("dev", Tier::Dev), command),
+            CommandVerdict::Allowed
+        );
+
+        let command = Some("aws s3 rm s3://bucket --recursive");
+        assert!(matches!(
+            settings.check_command(&profile("bg", Tier::BreakGlass), command),
+            CommandVerdict::Denied(_)
+        ));
+        assert_eq!(
+            settings.check_command(&profile("stg", Tier::Staging), command),
+            CommandVerdict::Allowed
+        );
+    }
+
+    #[test]
+    fn unlocking_for_a_duration_is_denied_by_allow_and_deny() {
+        let restricted = Settings::from_user_file(
+            r#"
+            [[command_rules]]
+            tier = "production"
+            allow = ["terraform plan*"]
+            "#,
+        );
+        let prod = profile("prod", Tier::Production);
+        // `None` stands for `--seconds` and `serve`, which allow any command
+        assert_eq!(
+            restricted.check_command(&prod, None),
+            CommandVerdict::Denied(
+                "its command rules only allow unlocking for a command".to_string()
+            )
+        );
+
+        let confirmed = Settings::from_user_file(
+            r#"
+            [[command_rules]]
+            tier = "production"
+            confirm = ["terraform apply*"]
+            "#,
+        );
+        assert_eq!(
+            confirmed.check_command(&prod, None),
+            CommandVerdict::Confirm(Pattern::new("terraform apply*").unwrap())
+        );
+        assert_eq!(
+            Settings::default().check_command(&prod, None),
+            CommandVerdict::Allowed
+        );
+    }
+
+    #[test]
+    fn production_rules_need_all_their_conditions() {
+        let settings = Settings::from_user_file(
+            r#"
+            [[production_rules]]
+            name = "prd-*"
+            regions = ["eu-west-1"]
+            "#,
+        );
+        let mut prd = profile("prd-billing", Tier::Dev);
+        prd.data.conf.region = Some("eu-west-1".to_string());
+        assert_eq!(
+            settings.match_rules(&prd),
+            Some(RuleMatch {
+                tier: Tier::Production,
+                reason: "name matches 'prd-*' and region eu-west-1".to_string(),
+            })
+        );
+
+        prd.data.conf.region = Some("us-east-1".to_string());
+        assert_eq!(settings.match_rules(&prd), None);
+
+        // User rules replace the default one
+        let team = profile("team-production", Tier::Dev);
+        assert_eq!(settings.match_rules(&team), None);
+        assert!(Settings::default().match_rules(&team).is_some());
+    }
+
+    #[test]
+    fn the_most_sensitive_matching_rule_wins() {
+        let settings = Settings::from_user_file(
+            r#"
+            [[production_rules]]
+            role_arn = "arn:aws:iam::*:role/Admin*"
+
+            [[production_rules]]
+            account_ids = ["123456789012"]
+            tier = "break-glass"
+            "#,
+        );
+        let mut admin = profile("admin", Tier::Dev);
+        admin.data.conf.role_arn = Some("arn:aws:iam::123456789012:role/AdminAccess".to_string());
+        assert_eq!(
+            settings.match_rules(&admin),
+            Some(RuleMatch {
+                tier: Tier::BreakGlass,
+                reason: "account 123456789012".to_string(),
+            })
+        );
+
+        admin.data.conf.role_arn = Some("arn:aws:iam::210987654321:role/AdminAccess".to_string());
+        assert_eq!(
+            settings
+                .match_rules(&admin)
+                .map(|rule_match| rule_match.tier),
+            Some(Tier::Production)
+        );
+    }
+
+    #[test]
+    fn project_settings_only_tighten_user_settings() {
+        let mut settings = Settings::from_user_file(
+            r#"
+            duration = "15m"
+
+            [tiers.staging]
+            confirm = true
+            max_duration = "1h"
+
+            [confirmation]
+            mode = "name"
+            "#,
+        );
+        apply_project(
+            &mut settings,
+            r#"
+            duration = "5m"
+            default_profile = "stg"
+
+            [tiers.staging]
+            confirm = false
+            max_duration = "2h"
+
+            [tiers.dev]
+            max_duration = "10m"
+
+            [confirmation]
+            mode = "yes"
+            reason = "required"
+
+            [[production_rules]]
+            name = "stg-*"
+            "#,
+        )
+        .unwrap();
+
+        // Defaults are overridden, as tier policies still bound them
+        assert_eq!(settings.duration, Duration::from_secs(5 * 60));
+        assert_eq!(settings.default_profile.as_deref(), Some("stg"));
+        let project = Path::new(PROJECT_SETTINGS_FILE);
+        assert_eq!(settings.source("duration"), Some(project));
+
+        let staging = settings.policy(Tier::Staging);
+        assert!(staging.confirm);
+        assert_eq!(staging.max_duration, Some(Duration::from_secs(60 * 60)));
+        assert_eq!(
+            settings.source("tiers.staging.confirm"),
+            Some(Path::new("config.toml"))
+        );
+        assert_eq!(
+            settings.policy(Tier::Dev).max_duration,
+            Some(Duration::from_secs(10 * 60))
+        );
+
+        assert_eq!(settings.confirmation.mode, ConfirmMode::Name);
+        assert_eq!(settings.confirmation.reason, ReasonPolicy::Required);
+
+        // Project rules are added to the default rule rather than replacing it
+        assert_eq!(settings.production_rules.len(), 2);
+    }
+
+    #[test]
+    fn project_settings_cannot_define_targets() {
+        for project in ["[groups]\nteam = [\"dev\"]", "[aliases]\nd = \"dev\""] {
+            let err = apply_project(&mut Settings::default(), project).unwrap_err();
+            assert!(
+                matches!(&err, Error::Settings { message, .. } if message.contains("only allowed in the user")),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn project_settings_restrict_the_profiles_allowed() {
+        let mut settings = Settings::default();
+        settings.check_allowed(&["prod".into()]).unwrap();
+
+        apply_project(
+            &mut settings,
+            r#"
+            [profiles]
+            allow = ["stg-*", "dev"]
+            deny = ["stg-admin"]
+            "#,
+        )
+        .unwrap();
+        settings
+            .check_allowed(&["dev".into(), "stg-app".into()])
+            .unwrap();
+
+        let res = settings.check_allowed(&["stg-admin".into(), "dev".into(), "prod".into()]);
+        assert!(matches!(
+            res,
+            Err(Error::NotAllowedHere { profiles, .. })
+                if profiles == [ProfileName::from("stg-admin"), ProfileName::from("prod")]
+        ));
+    }
+
+    #[test]
+    fn user_settings_cannot_restrict_profiles() {
+        let mut settings = Settings::default();
+        let file = toml::from_str("[profiles]\nallow = [\"dev\"]").unwrap();
+        let res = settings.apply(file, Path::new("config.toml"), false);
+        assert!(matches!(res, Err(Error::Settings { .. })));
+    }
+
+    #[test]
+    fn targets_expand_recursively_once_each() {
+        let settings = Settings::from_user_file(
+            r#"
+            [groups]
+            web = ["web-stg", "web-prd"]
+            all = ["@web", "api", "web-stg"]
+
+            [aliases]
+            api = "api-prd"
+            "#,
+        );
+        let expanded = settings
+            .expand_targets(&["@all".to_string(), "dev".to_string()])
+            .unwrap();
+        assert_eq!(
+            expanded,
+            ["web-stg", "web-prd", "api-prd", "dev"].map(ProfileName::from)
+        );
+
+        let res = settings.expand_targets(&["@none".to_string()]);
+        assert!(matches!(res, Err(Error::UnknownProfiles(_))));
+    }
+
+    #[test]
+    fn cycles_in_targets_are_reported() {
+        let settings = Settings::from_user_file(
+            r#"
+            [groups]
+            a = ["@b"]
+            b = ["dev", "c"]
+
+            [aliases]
+            c = "@a"
+            "#,
+        );
+        let err = settings.expand_targets(&["@a".to_string()]).unwrap_err();
+        assert!(
+            matches!(&err, Error::Settings { message, .. } if message == "groups.a refers to itself: @a -> @b -> c -> @a"),
+            "{err}"
+        );
+    }
+}
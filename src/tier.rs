@@ -0,0 +1,126 @@
+use serde::Deserialize;
+use std::{fmt, str::FromStr, time::Duration};
+
+/// Environment tier of a profile, set by a `# aws-unlock: tier=<tier>` marker above its header.
+/// `# production` is an alias for `tier=production`. Ordered from the least to the most
+/// sensitive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Tier {
+    /// Profiles without a marker.
+    #[default]
+    Dev,
+    Staging,
+    Production,
+    BreakGlass,
+}
+
+/// What is allowed when unlocking profiles of a tier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TierPolicy {
+    /// Ask before unlocking.
+    pub confirm: bool,
+
+    /// Longest time the profiles can be unlocked for with `--seconds` or `serve --for`.
+    pub max_duration: Option<Duration>,
+
+    /// Programs the profiles can be unlocked for. `None` allows any program as well as unlocking
+    /// for a duration; otherwise only exec mode with one of these programs is allowed.
+    pub commands: Option<Vec<String>>,
+
+    /// Ask for a TOTP code from the secret enrolled by `aws-unlock totp enroll`.
+    pub totp: bool,
+}
+
+impl Tier {
+    pub const ALL: [Tier; 4] = [Tier::Dev, Tier::Staging, Tier::Production, Tier::BreakGlass];
+
+    /// Policy of the tier unless overridden in the settings.
+    pub fn default_policy(self) -> TierPolicy {
+        match self {
+            Tier::Dev | Tier::Staging => TierPolicy {
+                confirm: false,
+                max_duration: None,
+                commands: None,
+                totp: false,
+            },
+            Tier::Production => TierPolicy {
+                confirm: true,
+                max_duration: None,
+                commands: None,
+                totp: false,
+            },
+            Tier::BreakGlass => TierPolicy {
+                confirm: true,
+                max_duration: Some(Duration::from_secs(60 * 60)),
+                commands: None,
+                totp: false,
+            },
+        }
+    }
+
+    /// Marker line written above the header, or `None` for [`Tier::Dev`] which needs none.
+    pub fn marker(self) -> Option<String> {
+        match self {
+            Tier::Dev => None,
+            Tier::Production => Some("# production".to_string()),
+            _ => Some(format!("# aws-unlock: tier={self}")),
+        }
+    }
+}
+
+impl TierPolicy {
+    /// Combines the policy with `other`, keeping the stricter of each.
+    pub fn tighten(&mut self, other: TierPolicy) {
+        self.confirm |= other.confirm;
+        self.totp |= other.totp;
+        self.max_duration = match (self.max_duration, other.max_duration) {
+            (Some(max), Some(other_max)) => Some(max.min(other_max)),
+            (max, other_max) => max.or(other_max),
+        };
+        self.commands = match (self.commands.take(), other.commands) {
+            (Some(commands), Some(other_commands)) => Some(
+                commands
+                    .into_iter()
+                    .filter(|command| other_commands.contains(command))
+                    .collect(),
+            ),
+            (commands, other_commands) => commands.or(other_commands),
+        };
+    }
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tier::Dev => write!(b, "dev"),
+            Tier::Staging => write!(b, "staging"),
+            Tier::Production => write!(b, "production"),
+            Tier::BreakGlass => write!(b, "break-glass"),
+        }
+    }
+}
+
+impl FromStr for Tier {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dev" | "development" => Ok(Tier::Dev),
+            "staging" | "stg" => Ok(Tier::Staging),
+            "production" | "prod" => Ok(Tier::Production),
+            "break-glass" | "breakglass" => Ok(Tier::BreakGlass),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryFrom<String> for Tier {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse().map_err(|()| {
+            format!("unknown tier {value:?}: expected dev, staging, production or break-glass")
+        })
+    }
+}
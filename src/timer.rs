@@ -1,10 +1,14 @@
-use std::time::{Duration, Instant};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
 use cancellable_timer::{Canceller, Timer};
-use tokio::task::spawn;
+use tokio::task::spawn_blocking;
 use tokio::time::sleep_until;
 
+use crate::error::Result;
+
 /// A sleep timer that can cancel sleep at any time and observe remaining time periodically.
 pub struct ObservableTimer {
     timer: Timer,
@@ -26,7 +30,7 @@ impl ObservableTimer {
         F: FnMut(Duration),
     {
         let start = Instant::now();
-        let entire_sleep = spawn(async move { self.timer.sleep(total_duration) });
+        let entire_sleep = spawn_blocking(move || self.timer.sleep(total_duration));
         tokio::pin!(entire_sleep);
 
         let mut next_inspection = start;
@@ -34,7 +38,7 @@ impl ObservableTimer {
             let inspection_sleep = sleep_until(next_inspection.into());
 
             tokio::select! {
-                end = &mut entire_sleep => return Ok(end??),
+                end = &mut entire_sleep => return Ok(end.map_err(io::Error::other)??),
                 _ = inspection_sleep => {
                     let elapsed = start.elapsed();
                     if total_duration > elapsed {
@@ -0,0 +1,264 @@
+use hmac::{Hmac, Mac};
+use qrcodegen::{QrCode, QrCodeEcc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    app_dir::{app_dir, write_private_file},
+    error::{Error, Result},
+};
+
+/// Name shown in authenticator apps.
+const ISSUER: &str = "aws-unlock";
+
+/// Seconds each code is valid for.
+const PERIOD: u64 = 30;
+
+/// Digits of each code.
+const DIGITS: u32 = 6;
+
+/// Steps before and after the current one whose codes are accepted, to allow for clock drift
+/// and codes typed just before they change.
+const WINDOW: i64 = 1;
+
+/// Shared secret for TOTP codes (RFC 6238, HMAC-SHA1, 6 digits, 30 seconds), kept in
+/// ~/.aws-unlock/totp.json readable only by the current user.
+pub struct Totp {
+    secret: Zeroizing<Vec<u8>>,
+
+    /// Last step a code was accepted for, so that a code cannot be used twice.
+    last_step: Option<u64>,
+}
+
+/// On-disk format of [`Totp`].
+#[derive(Serialize, Deserialize)]
+struct TotpFile {
+    /// Secret in unpadded base32, as in the otpauth URI.
+    secret: String,
+    last_step: Option<u64>,
+}
+
+impl fmt::Debug for Totp {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        b.debug_struct("Totp")
+            .field("last_step", &self.last_step)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Totp {
+    /// Generates a new random secret. It is not saved until [`Totp::save`].
+    pub fn generate() -> Totp {
+        let mut secret = Zeroizing::new(vec![0; 20]);
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        Totp {
+            secret,
+            last_step: None,
+        }
+    }
+
+    /// Loads the enrolled secret, or `None` if none is enrolled.
+    pub fn load() -> Result<Option<Totp>> {
+        let contents = match fs::read(totp_path()?) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let file: TotpFile = serde_json::from_slice(&contents)?;
+        let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, &file.secret)
+            .ok_or_else(|| Error::Totp("the enrolled secret is corrupted".to_string()))?;
+
+        Ok(Some(Totp {
+            secret: Zeroizing::new(secret),
+            last_step: file.last_step,
+        }))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let file = TotpFile {
+            secret: self.encoded_secret().to_string(),
+            last_step: self.last_step,
+        };
+        let contents = Zeroizing::new(serde_json::to_vec(&file)?);
+        write_private_file(&totp_path()?, &contents)
+    }
+
+    /// `otpauth://` URI to enroll the secret in an authenticator app.
+    pub fn uri(&self, account: &str) -> String {
+        format!(
+            "otpauth://totp/{ISSUER}:{account}?secret={}&issuer={ISSUER}&algorithm=SHA1\
+             &digits={DIGITS}&period={PERIOD}",
+            self.encoded_secret().as_str(),
+        )
+    }
+
+    /// Checks `code` against the codes of the steps around now, and records the step so that
+    /// the code is not accepted again. The caller saves the record with [`Totp::save`].
+    pub fn verify(&mut self, code: &str) -> bool {
+        let code = code.trim();
+        if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|dur| dur.as_secs())
+            .unwrap_or(0);
+        let current_step = (now / PERIOD) as i64;
+        for offset in -WINDOW..=WINDOW {
+            let Ok(step) = u64::try_from(current_step + offset) else {
+                continue;
+            };
+            if self.last_step.is_some_and(|last_step| step <= last_step) {
+                continue;
+            }
+            if constant_time_eq(self.code_at(step).as_bytes(), code.as_bytes()) {
+                self.last_step = Some(step);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Code for the step per RFC 4226, with the step as the counter.
+    fn code_at(&self, step: u64) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(DIGITS),
+            width = DIGITS as usize
+        )
+    }
+
+    fn encoded_secret(&self) -> Zeroizing<String> {
+        Zeroizing::new(base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &self.secret,
+        ))
+    }
+}
+
+/// Renders `text` as a QR code for the terminal, two modules per character line.
+pub fn render_qr(text: &str) -> Result<String> {
+    let qr = QrCode::encode_text(text, QrCodeEcc::Medium)
+        .map_err(|e| Error::Totp(format!("failed to encode the QR code: {e:?}")))?;
+
+    // Keep the quiet zone of 4 modules the QR spec requires, so that scanners find its edges
+    let border = 4;
+    let size = qr.size();
+    let is_dark = |x: i32, y: i32| qr.get_module(x, y);
+    let mut out = String::new();
+    for y in (-border..size + border).step_by(2) {
+        for x in -border..size + border {
+            // Light modules are drawn, for terminals with a dark background
+            let top = !is_dark(x, y);
+            let bottom = !is_dark(x, y + 1);
+            out.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+fn totp_path() -> Result<PathBuf> {
+    Ok(app_dir()?.join("totp.json"))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rfc6238_totp() -> Totp {
+        Totp {
+            secret: Zeroizing::new(b"12345678901234567890".to_vec()),
+            last_step: None,
+        }
+    }
+
+    fn now_step() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / PERIOD
+    }
+
+    /// SHA1 vectors of RFC 6238 Appendix B, reduced to the last 6 of their 8 digits.
+    #[test]
+    fn code_at_matches_rfc6238_vectors() {
+        let totp = rfc6238_totp();
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(totp.code_at(time / PERIOD), code, "at {time}");
+        }
+    }
+
+    #[test]
+    fn verify_rejects_reused_code() {
+        let mut totp = rfc6238_totp();
+        let code = totp.code_at(now_step());
+        assert!(totp.verify(&code));
+        assert!(!totp.verify(&code));
+    }
+
+    #[test]
+    fn verify_rejects_malformed_and_distant_codes() {
+        let mut totp = rfc6238_totp();
+        let step = now_step();
+        assert!(!totp.verify("12345"));
+        assert!(!totp.verify("abcdef"));
+        assert!(!totp.verify(&totp.code_at(step - 10)));
+        assert!(totp.verify(&format!(" {} ", totp.code_at(step))));
+    }
+
+    #[test]
+    fn render_qr_keeps_quiet_zone() {
+        let qr = render_qr("otpauth://totp/aws-unlock:test").unwrap();
+        let lines: Vec<_> = qr.lines().collect();
+        // 4 light modules are 2 full lines above the code and 4 full columns on each side
+        assert!(lines[..2].iter().all(|line| line.chars().all(|c| c == '█')));
+        for line in &lines {
+            let chars: Vec<_> = line.chars().collect();
+            assert!(chars[..4].iter().all(|&c| c == '█'));
+            assert!(chars[chars.len() - 4..].iter().all(|&c| c == '█'));
+        }
+    }
+}
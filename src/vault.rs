@@ -0,0 +1,286 @@
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, fmt, fs, io::ErrorKind, path::PathBuf};
+use zeroize::Zeroizing;
+
+use crate::{
+    app_dir::{app_dir, write_private_file},
+    aws_profile::{AwsCredentialData, ProfileName},
+    error::{Error, Result},
+    secret::Secret,
+};
+
+/// Value left in ~/.aws/credentials in place of secrets moved into the vault.
+pub const VAULT_PLACEHOLDER: &str = "<aws-unlock:vault>";
+
+/// Environment variable to read the vault passphrase from instead of prompting.
+pub const PASSPHRASE_ENV: &str = "AWS_UNLOCK_VAULT_PASSPHRASE";
+
+/// How locked credentials are kept in ~/.aws/credentials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockBackend {
+    /// Comment out the credentials, leaving them readable in plain text.
+    #[default]
+    Comment,
+
+    /// Move the secrets into the passphrase-encrypted vault, leaving placeholders behind.
+    Vault,
+}
+
+/// On-disk format of the vault: secrets of all profiles as one XChaCha20-Poly1305 encrypted
+/// JSON document, keyed by Argon2id from the passphrase.
+#[derive(Debug, Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Secret fields of a profile's credentials.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VaultSecrets {
+    aws_secret_access_key: Secret,
+    aws_session_token: Option<Secret>,
+    aws_security_token: Option<Secret>,
+}
+
+/// The decrypted vault. Secrets are encrypted again on [`Vault::save`] with the key derived when
+/// the vault was opened, so relocking does not ask for the passphrase again.
+pub struct Vault {
+    path: PathBuf,
+    salt: Vec<u8>,
+    cipher: XChaCha20Poly1305,
+    profiles: BTreeMap<String, VaultSecrets>,
+}
+
+impl fmt::Debug for Vault {
+    fn fmt(&self, b: &mut fmt::Formatter) -> fmt::Result {
+        b.debug_struct("Vault").field("path", &self.path).finish()
+    }
+}
+
+impl Vault {
+    /// Opens the vault with a passphrase from [`PASSPHRASE_ENV`] or the terminal, creating an
+    /// empty vault if there is none yet.
+    pub fn open() -> Result<Vault> {
+        Vault::open_at(app_dir()?.join("vault.json"), read_passphrase)
+    }
+
+    /// Opens the vault at `path` with the passphrase returned by `passphrase`, which is told
+    /// whether the vault is new.
+    fn open_at(
+        path: PathBuf,
+        passphrase: impl FnOnce(bool) -> Result<Zeroizing<String>>,
+    ) -> Result<Vault> {
+        let contents = match fs::read(&path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let Some(contents) = contents else {
+            let passphrase = passphrase(true)?;
+            let mut salt = vec![0; 16];
+            OsRng.fill_bytes(&mut salt);
+            let cipher = derive_cipher(&passphrase, &salt)?;
+            return Ok(Vault {
+                path,
+                salt,
+                cipher,
+                profiles: BTreeMap::new(),
+            });
+        };
+
+        let file: VaultFile = serde_json::from_slice(&contents)?;
+        if file.version != 1 {
+            return Err(Error::Vault(format!(
+                "unsupported vault version: {}",
+                file.version
+            )));
+        }
+
+        let decode = |field: &str| {
+            BASE64
+                .decode(field)
+                .map_err(|e| Error::Vault(format!("corrupted vault: {e}")))
+        };
+        let salt = decode(&file.salt)?;
+        let nonce = decode(&file.nonce)?;
+        let ciphertext = decode(&file.ciphertext)?;
+        let passphrase = passphrase(false)?;
+        let cipher = derive_cipher(&passphrase, &salt)?;
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+            .map(Zeroizing::new)
+            .map_err(|_| Error::WrongPassphrase)?;
+
+        Ok(Vault {
+            path,
+            salt,
+            cipher,
+            profiles: serde_json::from_slice(&plaintext)?,
+        })
+    }
+
+    /// Moves the secrets of the credentials into the vault and replaces them with
+    /// [`VAULT_PLACEHOLDER`]. Does nothing if they are already sealed.
+    pub fn seal(&mut self, name: &ProfileName, cred: &mut AwsCredentialData) {
+        if is_sealed(cred) {
+            return;
+        }
+
+        let placeholder = || Secret::new(VAULT_PLACEHOLDER);
+        let secrets = VaultSecrets {
+            aws_secret_access_key: std::mem::replace(
+                &mut cred.aws_secret_access_key,
+                placeholder(),
+            ),
+            aws_session_token: cred
+                .aws_session_token
+                .as_mut()
+                .map(|token| std::mem::replace(token, placeholder())),
+            aws_security_token: cred
+                .aws_security_token
+                .as_mut()
+                .map(|token| std::mem::replace(token, placeholder())),
+        };
+        self.profiles.insert(name.to_string(), secrets);
+    }
+
+    /// Puts the secrets of the profile back into sealed credentials.
+    pub fn unseal(&self, name: &ProfileName, cred: &mut AwsCredentialData) -> Result<()> {
+        if !is_sealed(cred) {
+            return Ok(());
+        }
+
+        let secrets = self
+            .profiles
+            .get(&name.to_string())
+            .ok_or_else(|| Error::Vault(format!("profile '{name}' is not found in the vault")))?;
+        cred.aws_secret_access_key = secrets.aws_secret_access_key.clone();
+        cred.aws_session_token = secrets.aws_session_token.clone();
+        cred.aws_security_token = secrets.aws_security_token.clone();
+
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plaintext = Zeroizing::new(serde_json::to_vec(&self.profiles)?);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_ref())
+            .map_err(|_| Error::Vault("failed to encrypt the vault".to_string()))?;
+        let file = VaultFile {
+            version: 1,
+            salt: BASE64.encode(&self.salt),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        };
+
+        write_private_file(&self.path, &serde_json::to_vec_pretty(&file)?)
+    }
+}
+
+/// Returns true if the secrets of the credentials are kept in the vault.
+pub fn is_sealed(cred: &AwsCredentialData) -> bool {
+    cred.aws_secret_access_key.expose() == VAULT_PLACEHOLDER
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = Zeroizing::new([0; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| Error::Vault(format!("failed to derive the vault key: {e}")))?;
+
+    Ok(XChaCha20Poly1305::new(key.as_ref().into()))
+}
+
+fn read_passphrase(is_new: bool) -> Result<Zeroizing<String>> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+
+    if !is_new {
+        return Ok(Zeroizing::new(rpassword::prompt_password(
+            "Vault passphrase: ",
+        )?));
+    }
+
+    let passphrase = Zeroizing::new(rpassword::prompt_password("New vault passphrase: ")?);
+    if passphrase.is_empty() {
+        return Err(Error::Vault(
+            "vault passphrase must not be empty".to_string(),
+        ));
+    }
+    if *Zeroizing::new(rpassword::prompt_password("Confirm vault passphrase: ")?) != *passphrase {
+        return Err(Error::Vault("vault passphrases do not match".to_string()));
+    }
+
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passphrase(passphrase: &str) -> impl FnOnce(bool) -> Result<Zeroizing<String>> + '_ {
+        move |_| Ok(Zeroizing::new(passphrase.to_string()))
+    }
+
+    #[test]
+    fn secrets_round_trip_through_the_vault() {
+        let dir = env::temp_dir().join(format!("aws-unlock-vault-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vault.json");
+        let _ = fs::remove_file(&path);
+
+        let name = ProfileName::from("vault-test");
+        let original = AwsCredentialData {
+            aws_access_key_id: "AKIAEXAMPLE".to_string(),
+            aws_secret_access_key: Secret::new("secret"),
+            aws_session_token: Some(Secret::new("token")),
+            ..Default::default()
+        };
+
+        let mut vault = Vault::open_at(path.clone(), passphrase("test passphrase")).unwrap();
+        let mut cred = original.clone();
+        vault.seal(&name, &mut cred);
+        assert!(is_sealed(&cred));
+        assert_eq!(cred.aws_access_key_id, "AKIAEXAMPLE");
+        assert_eq!(
+            cred.aws_session_token.as_ref().map(Secret::expose),
+            Some(VAULT_PLACEHOLDER)
+        );
+        // Sealing twice must not put the placeholders into the vault
+        vault.seal(&name, &mut cred);
+        vault.save().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("AKIAEXAMPLE") && !contents.contains(VAULT_PLACEHOLDER));
+
+        let reopened = Vault::open_at(path.clone(), passphrase("test passphrase")).unwrap();
+        reopened.unseal(&name, &mut cred).unwrap();
+        assert_eq!(cred, original);
+
+        let mut missing = AwsCredentialData {
+            aws_secret_access_key: Secret::new(VAULT_PLACEHOLDER),
+            ..Default::default()
+        };
+        let res = reopened.unseal(&ProfileName::from("missing"), &mut missing);
+        assert!(matches!(res, Err(Error::Vault(_))));
+
+        let res = Vault::open_at(path.clone(), passphrase("wrong passphrase"));
+        assert!(matches!(res, Err(Error::WrongPassphrase)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}